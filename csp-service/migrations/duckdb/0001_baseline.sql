@@ -0,0 +1,17 @@
+CREATE SEQUENCE IF NOT EXISTS csp_report_seq;
+CREATE TABLE IF NOT EXISTS csp_report (
+    source_ip TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT NOW(),
+    document_uri TEXT NOT NULL,
+    referrer TEXT NOT NULL,
+    violated_directive TEXT NOT NULL,
+    effective_directive TEXT NOT NULL,
+    original_policy TEXT NOT NULL,
+    disposition TEXT NOT NULL,
+    blocked_uri TEXT,
+    line_number UINTEGER,
+    column_number UINTEGER,
+    source_file TEXT,
+    status_code UINTEGER,
+    script_sample TEXT NOT NULL,
+);
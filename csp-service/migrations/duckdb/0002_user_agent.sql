@@ -0,0 +1,4 @@
+ALTER TABLE csp_report ADD COLUMN IF NOT EXISTS user_agent TEXT DEFAULT '';
+ALTER TABLE csp_report ADD COLUMN IF NOT EXISTS browser_family TEXT DEFAULT 'UNKNOWN';
+ALTER TABLE csp_report ADD COLUMN IF NOT EXISTS browser_version TEXT DEFAULT 'UNKNOWN';
+ALTER TABLE csp_report ADD COLUMN IF NOT EXISTS os TEXT DEFAULT 'UNKNOWN';
@@ -0,0 +1 @@
+ALTER TABLE csp_report ADD COLUMN IF NOT EXISTS sample_weight UINTEGER DEFAULT 1;
@@ -0,0 +1,72 @@
+CREATE TABLE IF NOT EXISTS coep_report (
+    source_ip TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT NOW(),
+    url TEXT NOT NULL,
+    user_agent TEXT NOT NULL,
+    coep_type TEXT,
+    blocked_url TEXT,
+    destination TEXT,
+    disposition TEXT,
+);
+CREATE TABLE IF NOT EXISTS coop_report (
+    source_ip TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT NOW(),
+    url TEXT NOT NULL,
+    user_agent TEXT NOT NULL,
+    coop_type TEXT,
+    disposition TEXT,
+    effective_policy TEXT,
+    property TEXT,
+    previous_response_url TEXT,
+    next_response_url TEXT,
+    other_document_url TEXT,
+    referrer TEXT,
+    source_file TEXT,
+    line_number UINTEGER,
+    column_number UINTEGER,
+);
+CREATE TABLE IF NOT EXISTS permissions_policy_report (
+    source_ip TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT NOW(),
+    url TEXT NOT NULL,
+    user_agent TEXT NOT NULL,
+    feature_id TEXT,
+    disposition TEXT,
+    message TEXT,
+    source_file TEXT,
+    line_number UINTEGER,
+    column_number UINTEGER,
+);
+CREATE TABLE IF NOT EXISTS deprecation_report (
+    source_ip TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT NOW(),
+    url TEXT NOT NULL,
+    user_agent TEXT NOT NULL,
+    deprecation_id TEXT,
+    anticipated_removal TEXT,
+    message TEXT,
+    source_file TEXT,
+    line_number UINTEGER,
+    column_number UINTEGER,
+);
+CREATE TABLE IF NOT EXISTS intervention_report (
+    source_ip TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT NOW(),
+    url TEXT NOT NULL,
+    user_agent TEXT NOT NULL,
+    intervention_id TEXT,
+    message TEXT,
+    source_file TEXT,
+    line_number UINTEGER,
+    column_number UINTEGER,
+);
+CREATE TABLE IF NOT EXISTS crash_report (
+    source_ip TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT NOW(),
+    url TEXT NOT NULL,
+    user_agent TEXT NOT NULL,
+    reason TEXT,
+    stack TEXT,
+    is_top_level BOOLEAN,
+    visibility_state TEXT,
+);
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS nel_report (
+    source_ip TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT NOW(),
+    url TEXT NOT NULL,
+    user_agent TEXT NOT NULL,
+    referrer TEXT,
+    sampling_fraction DOUBLE,
+    server_ip TEXT,
+    protocol TEXT,
+    method TEXT,
+    request_headers TEXT,
+    response_headers TEXT,
+    status_code UINTEGER,
+    elapsed_time UINTEGER,
+    phase TEXT,
+    nel_type TEXT,
+);
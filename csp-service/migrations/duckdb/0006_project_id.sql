@@ -0,0 +1,8 @@
+ALTER TABLE csp_report ADD COLUMN IF NOT EXISTS project_id BIGINT;
+ALTER TABLE coep_report ADD COLUMN IF NOT EXISTS project_id BIGINT;
+ALTER TABLE coop_report ADD COLUMN IF NOT EXISTS project_id BIGINT;
+ALTER TABLE permissions_policy_report ADD COLUMN IF NOT EXISTS project_id BIGINT;
+ALTER TABLE deprecation_report ADD COLUMN IF NOT EXISTS project_id BIGINT;
+ALTER TABLE intervention_report ADD COLUMN IF NOT EXISTS project_id BIGINT;
+ALTER TABLE crash_report ADD COLUMN IF NOT EXISTS project_id BIGINT;
+ALTER TABLE nel_report ADD COLUMN IF NOT EXISTS project_id BIGINT;
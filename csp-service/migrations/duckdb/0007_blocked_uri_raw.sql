@@ -0,0 +1 @@
+ALTER TABLE csp_report ADD COLUMN IF NOT EXISTS blocked_uri_raw TEXT;
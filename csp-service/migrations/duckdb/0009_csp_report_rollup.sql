@@ -0,0 +1,29 @@
+CREATE TABLE IF NOT EXISTS csp_report_rollup (
+    project_id BIGINT,
+    violated_directive TEXT NOT NULL,
+    effective_directive TEXT NOT NULL,
+    original_policy TEXT NOT NULL,
+    disposition TEXT NOT NULL,
+    blocked_uri TEXT,
+    source_file TEXT,
+    script_sample TEXT NOT NULL,
+    first_seen TIMESTAMP NOT NULL,
+    last_seen TIMESTAMP NOT NULL,
+    cnt UBIGINT NOT NULL,
+);
+DELETE FROM csp_report_rollup;
+INSERT INTO csp_report_rollup
+SELECT
+    project_id,
+    violated_directive,
+    effective_directive,
+    original_policy,
+    disposition,
+    blocked_uri,
+    source_file,
+    script_sample,
+    MIN(created_at),
+    MAX(created_at),
+    CAST(SUM(sample_weight) AS UBIGINT)
+FROM csp_report
+GROUP BY 1, 2, 3, 4, 5, 6, 7, 8;
@@ -0,0 +1,2 @@
+ALTER TABLE csp_report ADD COLUMN IF NOT EXISTS fingerprint TEXT;
+ALTER TABLE csp_report_rollup ADD COLUMN IF NOT EXISTS fingerprint TEXT;
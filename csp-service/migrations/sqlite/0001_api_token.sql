@@ -0,0 +1 @@
+CREATE TABLE IF NOT EXISTS api_token ( id INTEGER PRIMARY KEY, prefix TEXT NOT NULL, hash TEXT NOT NULL );
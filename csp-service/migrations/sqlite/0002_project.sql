@@ -0,0 +1 @@
+CREATE TABLE IF NOT EXISTS project ( id INTEGER PRIMARY KEY, name TEXT NOT NULL, key TEXT NOT NULL UNIQUE, created_at TEXT NOT NULL );
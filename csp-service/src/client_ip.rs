@@ -0,0 +1,213 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use ipnet::IpNet;
+
+use crate::{listen::PeerAddr, state::AppState};
+
+/// The address of the client that sent a request, resolved through any trusted proxies.
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let peer = match parts.extensions.get::<ConnectInfo<PeerAddr>>() {
+            Some(ConnectInfo(PeerAddr::Tcp(addr))) => Peer::Ip(addr.ip().to_canonical()),
+            Some(ConnectInfo(PeerAddr::Unix)) => Peer::Local,
+            None => return Ok(ClientIp(None)),
+        };
+        Ok(ClientIp(resolve_client_ip(
+            peer,
+            &parts.headers,
+            &state.trusted_proxies,
+        )))
+    }
+}
+
+pub fn parse_trusted_proxies(value: &str) -> Result<Vec<IpNet>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|e| {
+            e.parse::<IpNet>()
+                .or_else(|_| e.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("Invalid trusted proxy address: {}", e))
+        })
+        .collect()
+}
+
+enum Peer {
+    Ip(IpAddr),
+    /// A Unix socket peer, a proxy on the same host that is always trusted.
+    Local,
+}
+
+/// Walks the forwarding chain from the connected peer backwards, skipping
+/// every hop that is a trusted proxy. The first untrusted hop is the client.
+fn resolve_client_ip(peer: Peer, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    let mut client = match peer {
+        Peer::Ip(ip) if !is_trusted(&ip) => return Some(ip),
+        Peer::Ip(ip) => Some(ip),
+        Peer::Local => None,
+    };
+    for hop in forwarded_chain(headers).into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = Some(ip);
+                if !is_trusted(&ip) {
+                    break;
+                }
+            }
+            // An obfuscated or malformed hop can't be trusted any further.
+            None => break,
+        }
+    }
+    client
+}
+
+/// Returns the hops recorded by proxies, in the order they were appended.
+/// The standard `Forwarded` header takes precedence over `X-Forwarded-For`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<Option<IpAddr>> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|e| e.to_str().ok())
+        .flat_map(|e| e.split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_node(value))
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|e| e.to_str().ok())
+        .flat_map(|e| e.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Parses a node such as `1.2.3.4`, `1.2.3.4:80`, `"[2001:db8::1]:80"` or `2001:db8::1`.
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|e| e.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_nodes() {
+        assert_eq!(parse_node(" 1.2.3.4 "), Some(ip("1.2.3.4")));
+        assert_eq!(parse_node("1.2.3.4:80"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_node("\"[2001:db8::1]:80\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("unknown"), None);
+    }
+
+    #[test]
+    fn parses_trusted_proxies() {
+        let proxies = parse_trusted_proxies("10.0.0.0/8, 192.168.1.1,,::1").unwrap();
+        assert_eq!(proxies.len(), 3);
+        assert!(proxies[1].contains(&ip("192.168.1.1")));
+        assert!(parse_trusted_proxies("not-an-ip").is_err());
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "9.9.9.9".parse().unwrap());
+        let trusted = parse_trusted_proxies("10.0.0.1").unwrap();
+        assert_eq!(
+            resolve_client_ip(Peer::Ip(ip("8.8.8.8")), &headers, &trusted),
+            Some(ip("8.8.8.8"))
+        );
+    }
+
+    #[test]
+    fn skips_trusted_hops() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "6.6.6.6, 9.9.9.9, 10.0.0.2".parse().unwrap(),
+        );
+        let trusted = parse_trusted_proxies("10.0.0.0/8").unwrap();
+        assert_eq!(
+            resolve_client_ip(Peer::Ip(ip("10.0.0.1")), &headers, &trusted),
+            Some(ip("9.9.9.9"))
+        );
+    }
+
+    #[test]
+    fn forwarded_takes_precedence() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "9.9.9.9".parse().unwrap());
+        headers.insert(
+            "forwarded",
+            "for=7.7.7.7;proto=https, For=\"[2001:db8::1]:443\""
+                .parse()
+                .unwrap(),
+        );
+        let trusted = parse_trusted_proxies("10.0.0.1").unwrap();
+        assert_eq!(
+            resolve_client_ip(Peer::Ip(ip("10.0.0.1")), &headers, &trusted),
+            Some(ip("2001:db8::1"))
+        );
+    }
+
+    #[test]
+    fn stops_at_obfuscated_hops() {
+        let mut headers = HeaderMap::new();
+        headers.insert("forwarded", "for=7.7.7.7, for=_proxy".parse().unwrap());
+        let trusted = parse_trusted_proxies("10.0.0.1").unwrap();
+        assert_eq!(
+            resolve_client_ip(Peer::Ip(ip("10.0.0.1")), &headers, &trusted),
+            Some(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn trusts_unix_socket_peers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(resolve_client_ip(Peer::Local, &headers, &[]), None);
+        headers.insert("x-forwarded-for", "9.9.9.9".parse().unwrap());
+        assert_eq!(
+            resolve_client_ip(Peer::Local, &headers, &[]),
+            Some(ip("9.9.9.9"))
+        );
+    }
+}
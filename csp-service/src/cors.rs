@@ -0,0 +1,134 @@
+use std::{env, time::Duration};
+
+use axum::http::{header, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Parses a comma separated list of origins, `*` allows any origin.
+fn parse_allowed_origins(value: &str) -> Result<AllowOrigin, String> {
+    if value.trim() == "*" {
+        return Ok(AllowOrigin::any());
+    }
+    let origins = value
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|e| {
+            HeaderValue::from_str(e.trim_end_matches('/'))
+                .map_err(|_| format!("Invalid CORS origin: {}", e))
+        })
+        .collect::<Result<Vec<HeaderValue>, String>>()?;
+    Ok(AllowOrigin::list(origins))
+}
+
+/// CORS for the public report endpoint. Any origin is allowed unless
+/// `METLO_CORS_ALLOWED_ORIGINS` says otherwise.
+pub fn ingest_cors_layer() -> Result<CorsLayer, String> {
+    ingest_cors(env::var("METLO_CORS_ALLOWED_ORIGINS").ok().as_deref())
+}
+
+fn ingest_cors(allowed_origins: Option<&str>) -> Result<CorsLayer, String> {
+    Ok(CorsLayer::new()
+        .allow_origin(parse_allowed_origins(allowed_origins.unwrap_or("*"))?)
+        .allow_methods([Method::POST])
+        .allow_headers([header::CONTENT_TYPE, header::CONTENT_ENCODING])
+        .max_age(PREFLIGHT_MAX_AGE))
+}
+
+/// CORS for the authenticated API. The API stays same-origin unless
+/// `METLO_API_CORS_ALLOWED_ORIGINS` is set.
+pub fn api_cors_layer() -> Result<Option<CorsLayer>, String> {
+    api_cors(env::var("METLO_API_CORS_ALLOWED_ORIGINS").ok().as_deref())
+}
+
+fn api_cors(allowed_origins: Option<&str>) -> Result<Option<CorsLayer>, String> {
+    let allowed_origins = match allowed_origins {
+        Some(e) if !e.trim().is_empty() => e,
+        _ => return Ok(None),
+    };
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(parse_allowed_origins(allowed_origins)?)
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+            .max_age(PREFLIGHT_MAX_AGE),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::post,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// The `Access-Control-Allow-Origin` a report sent from `origin` gets back.
+    async fn allowed_origin(layer: CorsLayer, origin: &str) -> Option<String> {
+        let app = Router::new()
+            .route("/", post(|| async { StatusCode::OK }))
+            .layer(layer);
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(header::ORIGIN, origin)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        res.headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|e| e.to_str().unwrap().to_owned())
+    }
+
+    #[tokio::test]
+    async fn ingest_allows_any_origin_by_default() {
+        for allowed_origins in [None, Some("*"), Some(" * ")] {
+            let layer = ingest_cors(allowed_origins).unwrap();
+            assert_eq!(
+                allowed_origin(layer, "https://example.com")
+                    .await
+                    .as_deref(),
+                Some("*")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn origin_lists_allow_only_their_origins() {
+        let allowed_origins = Some("https://a.com/, https://b.com,,");
+        for origin in ["https://a.com", "https://b.com"] {
+            let layer = ingest_cors(allowed_origins).unwrap();
+            assert_eq!(allowed_origin(layer, origin).await.as_deref(), Some(origin));
+        }
+        let layer = ingest_cors(allowed_origins).unwrap();
+        assert_eq!(allowed_origin(layer, "https://c.com").await, None);
+    }
+
+    #[test]
+    fn rejects_invalid_origins() {
+        let err = parse_allowed_origins("https://a.com,https://b\ncom").unwrap_err();
+        assert!(err.contains("Invalid CORS origin"), "{}", err);
+        assert!(ingest_cors(Some("https://a\n.com")).is_err());
+        assert!(api_cors(Some("https://a\n.com")).is_err());
+    }
+
+    #[tokio::test]
+    async fn api_cors_is_off_unless_configured() {
+        assert!(api_cors(None).unwrap().is_none());
+        assert!(api_cors(Some(" ")).unwrap().is_none());
+        let layer = api_cors(Some("https://admin.example.com"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            allowed_origin(layer, "https://admin.example.com")
+                .await
+                .as_deref(),
+            Some("https://admin.example.com")
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use axum::{
+    body::StreamBody,
+    extract::{self, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use rand::Rng;
+use serde::Deserialize;
+use tokio_util::io::ReaderStream;
+
+use crate::{project::ProjectFilter, state::AppState, utils::internal_error};
+
+/// Which reports to export, every stored one when unset.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportQueryParams {
+    /// Inclusive start, an RFC 3339 timestamp or a `YYYY-MM-DD` day in UTC.
+    pub from: Option<String>,
+    /// Exclusive end, in the same format as `from`.
+    pub to: Option<String>,
+    /// Write one file per day, in hive style `day=YYYY-MM-DD` directories.
+    #[serde(default)]
+    pub partition_by_day: bool,
+}
+
+fn parse_time(name: &str, value: &str) -> Result<String, String> {
+    let time = match chrono::DateTime::parse_from_rfc3339(value) {
+        Ok(e) => e.naive_utc(),
+        Err(_) => chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .ok()
+            .and_then(|e| e.and_hms_opt(0, 0, 0))
+            .ok_or(format!("Invalid {}: {}", name, value))?,
+    };
+    Ok(time.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+}
+
+/// The statement writing the selected reports to `path`. DuckDB doesn't take
+/// parameters in `COPY`, so the values are checked and formatted here instead.
+fn copy_sql(
+    params: &ExportQueryParams,
+    project: &ProjectFilter,
+    path: &Path,
+) -> Result<String, String> {
+    let mut conditions = vec![];
+    if let Some(from) = &params.from {
+        conditions.push(format!(
+            "created_at >= TIMESTAMP '{}'",
+            parse_time("from", from)?
+        ));
+    }
+    if let Some(to) = &params.to {
+        conditions.push(format!(
+            "created_at < TIMESTAMP '{}'",
+            parse_time("to", to)?
+        ));
+    }
+    if let Some(project_id) = project.project_id {
+        conditions.push(format!("project_id = {}", project_id));
+    }
+    if conditions.is_empty() {
+        conditions.push("TRUE".to_string());
+    }
+
+    let path = path
+        .to_str()
+        .ok_or(format!("Invalid export path: {}", path.display()))?
+        .replace('\'', "''");
+    let (day, options) = if params.partition_by_day {
+        (", CAST(created_at AS DATE) AS day", ", PARTITION_BY (day)")
+    } else {
+        ("", "")
+    };
+    Ok(format!(
+        "COPY (
+            SELECT *{day} FROM csp_report WHERE {} ORDER BY created_at
+        ) TO '{path}' (FORMAT PARQUET{options})",
+        conditions.join(" AND ")
+    ))
+}
+
+/// Writes the selected reports to `path` as Parquet, a single file or, when
+/// partitioned by day, a directory of them.
+pub fn export_parquet(
+    conn: &duckdb::Connection,
+    params: &ExportQueryParams,
+    project: &ProjectFilter,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    conn.execute_batch(&copy_sql(params, project, path)?)?;
+    Ok(())
+}
+
+/// A scratch directory under `METLO_DATA_PATH`, removed when dropped.
+struct ExportDir(PathBuf);
+
+impl ExportDir {
+    fn create(data_path: &Path) -> Result<Self, std::io::Error> {
+        let name: String = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let path = data_path.join("exports").join(name);
+        fs::create_dir_all(&path)?;
+        Ok(ExportDir(path))
+    }
+}
+
+impl Drop for ExportDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Exports into a scratch directory and opens the result, a tar archive when
+/// partitioned. The directory is gone once this returns, the open file stays
+/// readable until it is closed.
+fn export_file(
+    state: &AppState,
+    params: &ExportQueryParams,
+    project: &ProjectFilter,
+) -> Result<fs::File, Box<dyn std::error::Error + Send + Sync>> {
+    let dir = ExportDir::create(&state.data_path)?;
+    let conn = state.duckdb_pool.get()?;
+    if !params.partition_by_day {
+        let path = dir.0.join("csp_reports.parquet");
+        export_parquet(&conn, params, project, &path)?;
+        return Ok(fs::File::open(path)?);
+    }
+
+    let parts = dir.0.join("csp_reports");
+    export_parquet(&conn, params, project, &parts)?;
+    drop(conn);
+    let path = dir.0.join("csp_reports.tar");
+    let mut archive = tar::Builder::new(fs::File::create(&path)?);
+    archive.append_dir_all("csp_reports", &parts)?;
+    archive.into_inner()?.sync_all()?;
+    Ok(fs::File::open(path)?)
+}
+
+pub async fn get_parquet_export(
+    State(state): State<AppState>,
+    extract::Query(params): extract::Query<ExportQueryParams>,
+    extract::Query(project): extract::Query<ProjectFilter>,
+) -> Result<Response, (StatusCode, String)> {
+    for (name, value) in [("from", &params.from), ("to", &params.to)] {
+        if let Some(value) = value {
+            parse_time(name, value).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        }
+    }
+    let partition_by_day = params.partition_by_day;
+    let file = tokio::task::spawn_blocking(move || export_file(&state, &params, &project))
+        .await
+        .map_err(internal_error)?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let (content_type, file_name) = if partition_by_day {
+        ("application/x-tar", "csp_reports.tar")
+    } else {
+        ("application/vnd.apache.parquet", "csp_reports.parquet")
+    };
+    let body = StreamBody::new(ReaderStream::new(tokio::fs::File::from_std(file)));
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{migrated_duckdb, TempDir};
+
+    fn insert(conn: &duckdb::Connection, created_at: &str, document_uri: &str, project_id: i64) {
+        conn.execute(
+            "INSERT INTO csp_report (
+                source_ip, created_at, document_uri, referrer, violated_directive,
+                effective_directive, original_policy, disposition, script_sample, project_id
+            ) VALUES ('', CAST(? AS TIMESTAMP), ?, '', '', '', '', 'report', '', ?)",
+            duckdb::params![created_at, document_uri, project_id],
+        )
+        .unwrap();
+    }
+
+    fn reports_conn() -> duckdb::Connection {
+        let conn = migrated_duckdb();
+        insert(&conn, "2023-07-01 10:00:00", "https://a.com/1", 1);
+        insert(&conn, "2023-07-01 12:00:00", "https://b.com/1", 2);
+        insert(&conn, "2023-07-02 01:00:00", "https://a.com/2", 1);
+        conn
+    }
+
+    #[test]
+    fn parses_rfc3339_and_days() {
+        assert_eq!(
+            parse_time("from", "2023-07-01T12:30:00+02:00").unwrap(),
+            "2023-07-01 10:30:00.000"
+        );
+        assert_eq!(
+            parse_time("from", "2023-07-01").unwrap(),
+            "2023-07-01 00:00:00.000"
+        );
+        for value in [
+            "2023-07-01 12:30",
+            "07/01/2023",
+            "2023-02-30",
+            "'; DROP TABLE",
+        ] {
+            assert_eq!(
+                parse_time("to", value).unwrap_err(),
+                format!("Invalid to: {}", value)
+            );
+        }
+    }
+
+    #[test]
+    fn filters_by_time_and_project() {
+        let params = ExportQueryParams {
+            from: Some("2023-07-01".to_string()),
+            to: Some("2023-07-02T00:00:00Z".to_string()),
+            partition_by_day: false,
+        };
+        let project = ProjectFilter {
+            project_id: Some(7),
+        };
+        let sql = copy_sql(&params, &project, Path::new("/tmp/o'brien.parquet")).unwrap();
+        assert!(sql.contains(
+            "WHERE created_at >= TIMESTAMP '2023-07-01 00:00:00.000' \
+             AND created_at < TIMESTAMP '2023-07-02 00:00:00.000' AND project_id = 7"
+        ));
+        assert!(sql.contains("TO '/tmp/o''brien.parquet' (FORMAT PARQUET)"));
+    }
+
+    #[test]
+    fn exports_everything_by_default() {
+        let params = ExportQueryParams {
+            partition_by_day: true,
+            ..Default::default()
+        };
+        let sql = copy_sql(
+            &params,
+            &ProjectFilter { project_id: None },
+            Path::new("/tmp/parts"),
+        )
+        .unwrap();
+        assert!(sql.contains("WHERE TRUE"));
+        assert!(sql.contains("CAST(created_at AS DATE) AS day"));
+        assert!(sql.contains("(FORMAT PARQUET, PARTITION_BY (day))"));
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        let params = ExportQueryParams {
+            from: Some("yesterday".to_string()),
+            ..Default::default()
+        };
+        let err = copy_sql(
+            &params,
+            &ProjectFilter { project_id: None },
+            Path::new("/tmp/o.parquet"),
+        )
+        .unwrap_err();
+        assert_eq!(err, "Invalid from: yesterday");
+    }
+
+    #[test]
+    fn exports_selected_reports_to_a_file() {
+        let conn = reports_conn();
+        let dir = TempDir::new();
+        let path = dir.path().join("csp_reports.parquet");
+        let params = ExportQueryParams {
+            to: Some("2023-07-02".to_string()),
+            ..Default::default()
+        };
+        let project = ProjectFilter {
+            project_id: Some(1),
+        };
+        export_parquet(&conn, &params, &project, &path).unwrap();
+
+        let mut stmt = conn
+            .prepare("SELECT document_uri, project_id FROM read_parquet(?)")
+            .unwrap();
+        let rows: Vec<(String, i64)> = stmt
+            .query_map([path.to_str().unwrap()], |e| Ok((e.get(0)?, e.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows, vec![("https://a.com/1".to_string(), 1)]);
+    }
+
+    #[test]
+    fn exports_one_directory_per_day() {
+        let conn = reports_conn();
+        let dir = TempDir::new();
+        let path = dir.path().join("csp_reports");
+        let params = ExportQueryParams {
+            partition_by_day: true,
+            ..Default::default()
+        };
+        export_parquet(&conn, &params, &ProjectFilter { project_id: None }, &path).unwrap();
+
+        let mut days: Vec<String> = fs::read_dir(&path)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        days.sort();
+        assert_eq!(days, vec!["day=2023-07-01", "day=2023-07-02"]);
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT CAST(day AS TEXT), document_uri
+                FROM read_parquet(?, hive_partitioning = true) ORDER BY 2",
+            )
+            .unwrap();
+        let glob = path.join("*").join("*.parquet");
+        let rows: Vec<(String, String)> = stmt
+            .query_map([glob.to_str().unwrap()], |e| Ok((e.get(0)?, e.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("2023-07-01".to_string(), "https://a.com/1".to_string()),
+                ("2023-07-02".to_string(), "https://a.com/2".to_string()),
+                ("2023-07-01".to_string(), "https://b.com/1".to_string()),
+            ]
+        );
+    }
+}
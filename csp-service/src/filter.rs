@@ -0,0 +1,269 @@
+use std::{
+    env, fs,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::report::BufferItem;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FilterField {
+    BlockedUri,
+    SourceFile,
+    ScriptSample,
+}
+
+const ALL_FIELDS: [FilterField; 3] = [
+    FilterField::BlockedUri,
+    FilterField::SourceFile,
+    FilterField::ScriptSample,
+];
+
+/// A rule as written in the file at `METLO_FILTER_RULES_PATH`. `pattern` is a
+/// regex, `fields` defaults to every field.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterRuleConfig {
+    pub name: String,
+    pub pattern: String,
+    #[serde(default)]
+    pub fields: Vec<FilterField>,
+}
+
+/// Violations caused by the browser, extensions or software on the visitor's
+/// machine rather than by the site.
+const DEFAULT_RULES: [(&str, &str, &[FilterField]); 4] = [
+    (
+        "browser-extension",
+        r"^(chrome|moz|safari|safari-web|ms-browser|edge)-extension:",
+        &[FilterField::BlockedUri, FilterField::SourceFile],
+    ),
+    (
+        "browser-internal",
+        r"^(about|chrome|resource|webkit-masked-url|chromeinvoke|chromenull|ms-appx-web|mxaddon-pkg|jar|tmtbff|gsa|webviewprogressproxy)(:|$)",
+        &[FilterField::BlockedUri, FilterField::SourceFile],
+    ),
+    (
+        "antivirus-injection",
+        r"(kaspersky-labs\.com|drweb\.com|bitdefender\.net|avast\.com|norton\.com)",
+        &[FilterField::BlockedUri, FilterField::SourceFile],
+    ),
+    (
+        "injected-script",
+        r"(__gCrWeb|__firefox__|_AutofillCallbackHandler|__REACT_DEVTOOLS_GLOBAL_HOOK__)",
+        &[FilterField::ScriptSample],
+    ),
+];
+
+struct FilterRule {
+    name: String,
+    regex: Regex,
+    fields: Vec<FilterField>,
+    dropped: AtomicU64,
+}
+
+impl FilterRule {
+    fn matches(&self, item: &BufferItem) -> bool {
+        self.fields.iter().any(|field| {
+            let value = match field {
+                FilterField::BlockedUri => item
+                    .blocked_uri_raw
+                    .as_deref()
+                    .or(item.blocked_uri.as_deref()),
+                FilterField::SourceFile => item.source_file.as_deref(),
+                FilterField::ScriptSample => Some(item.script_sample.as_str()),
+            };
+            value.is_some_and(|e| self.regex.is_match(e))
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterCount {
+    pub rule: String,
+    pub dropped: u64,
+}
+
+/// Drops reports matching any of its rules at ingest, counting drops per rule.
+pub struct NoiseFilter {
+    rules: Vec<FilterRule>,
+}
+
+fn default_rules() -> impl Iterator<Item = FilterRuleConfig> {
+    DEFAULT_RULES
+        .iter()
+        .map(|(name, pattern, fields)| FilterRuleConfig {
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            fields: fields.to_vec(),
+        })
+}
+
+impl NoiseFilter {
+    pub fn new(configs: Vec<FilterRuleConfig>) -> Result<Self, String> {
+        let rules = configs
+            .into_iter()
+            .map(|config| {
+                let regex = Regex::new(&config.pattern).map_err(|e| {
+                    format!("Invalid pattern for filter rule {}: {}", config.name, e)
+                })?;
+                let fields = if config.fields.is_empty() {
+                    ALL_FIELDS.to_vec()
+                } else {
+                    config.fields
+                };
+                Ok(FilterRule {
+                    name: config.name,
+                    regex,
+                    fields,
+                    dropped: AtomicU64::new(0),
+                })
+            })
+            .collect::<Result<Vec<FilterRule>, String>>()?;
+        Ok(NoiseFilter { rules })
+    }
+
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut configs: Vec<FilterRuleConfig> = vec![];
+        let use_defaults = env::var("METLO_FILTER_DEFAULT_RULES")
+            .map(|e| e != "false")
+            .unwrap_or(true);
+        if use_defaults {
+            configs.extend(default_rules());
+        }
+        if let Ok(path) = env::var("METLO_FILTER_RULES_PATH") {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Error reading filter rules from {}: {}", path, e))?;
+            let user_rules: Vec<FilterRuleConfig> = serde_json::from_str(&contents)
+                .map_err(|e| format!("Error parsing filter rules from {}: {}", path, e))?;
+            configs.extend(user_rules);
+        }
+        Ok(NoiseFilter::new(configs)?)
+    }
+
+    /// Returns true if the report should be kept.
+    pub fn keep(&self, item: &BufferItem) -> bool {
+        match self.rules.iter().find(|rule| rule.matches(item)) {
+            Some(rule) => {
+                rule.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            None => true,
+        }
+    }
+
+    pub fn counts(&self) -> Vec<FilterCount> {
+        self.rules
+            .iter()
+            .map(|rule| FilterCount {
+                rule: rule.name.clone(),
+                dropped: rule.dropped.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> NoiseFilter {
+        NoiseFilter::new(default_rules().collect()).unwrap()
+    }
+
+    #[test]
+    fn default_rules_drop_noise() {
+        let filter = defaults();
+        for (blocked_uri, source_file, script_sample) in [
+            (Some("chrome-extension://abcdef/inject.js"), None, ""),
+            (None, Some("moz-extension://1234/content.js"), ""),
+            (Some("about"), None, ""),
+            (Some("webkit-masked-url://hidden/"), None, ""),
+            (Some("https://gc.kaspersky-labs.com/main.js"), None, ""),
+            (Some("inline"), None, "try { __gCrWeb.autofill"),
+        ] {
+            let item = BufferItem {
+                blocked_uri: blocked_uri.map(str::to_string),
+                source_file: source_file.map(str::to_string),
+                script_sample: script_sample.to_string(),
+                ..Default::default()
+            };
+            assert!(!filter.keep(&item), "{:?}", item);
+        }
+    }
+
+    #[test]
+    fn default_rules_keep_site_violations() {
+        let filter = defaults();
+        for (blocked_uri, source_file, script_sample) in [
+            (Some("https://cdn.example.com/app.js"), None, ""),
+            (Some("inline"), Some("https://example.com/"), "alert(1)"),
+            (Some("eval"), None, ""),
+            (Some("data"), Some("https://chrome.example.com/a.js"), ""),
+        ] {
+            let item = BufferItem {
+                blocked_uri: blocked_uri.map(str::to_string),
+                source_file: source_file.map(str::to_string),
+                script_sample: script_sample.to_string(),
+                ..Default::default()
+            };
+            assert!(filter.keep(&item), "{:?}", item);
+        }
+        assert!(filter.counts().iter().all(|e| e.dropped == 0));
+    }
+
+    #[test]
+    fn user_rules_and_counts() {
+        let configs: Vec<FilterRuleConfig> = serde_json::from_str(
+            r#"[
+                {"name": "ads", "pattern": "doubleclick\\.net", "fields": ["blockedUri"]},
+                {"name": "tracker", "pattern": "tracker"}
+            ]"#,
+        )
+        .unwrap();
+        let filter = NoiseFilter::new(configs).unwrap();
+
+        let ad = BufferItem {
+            blocked_uri: Some("https://ad.doubleclick.net/x".to_string()),
+            ..Default::default()
+        };
+        assert!(!filter.keep(&ad));
+        assert!(!filter.keep(&ad));
+        // `fields` limits the ads rule to the blocked URI.
+        let ad_source = BufferItem {
+            source_file: Some("https://ad.doubleclick.net/x".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.keep(&ad_source));
+        // Without `fields`, every field is matched.
+        let tracker = BufferItem {
+            script_sample: "tracker.init()".to_string(),
+            ..Default::default()
+        };
+        assert!(!filter.keep(&tracker));
+
+        let counts: Vec<(String, u64)> = filter
+            .counts()
+            .into_iter()
+            .map(|e| (e.rule, e.dropped))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("ads".to_string(), 2), ("tracker".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let configs = vec![FilterRuleConfig {
+            name: "broken".to_string(),
+            pattern: "(".to_string(),
+            fields: vec![],
+        }];
+        assert!(NoiseFilter::new(configs).is_err());
+    }
+}
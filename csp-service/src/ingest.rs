@@ -0,0 +1,611 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use axum::http::StatusCode;
+use log::error;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch, Mutex, MutexGuard};
+
+use crate::{
+    metrics::Metrics,
+    report::{append_buffer_items, BufferItem},
+    report_types::{append_typed_reports, TypedReport},
+    sampler::Sampler,
+    spool::Spool,
+    state::AppState,
+    utils::internal_error,
+};
+
+const QUEUE_SIZE_DEFAULT: usize = 10000;
+const BLOCK_TIMEOUT_MS_DEFAULT: u64 = 100;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do with a report when the ingest queue is full.
+#[derive(Debug, Clone, Copy)]
+pub enum OverflowPolicy {
+    /// Acknowledge the report but throw it away.
+    Drop,
+    /// Respond with 503 so the sender knows the report was not stored.
+    Reject,
+    /// Wait up to the given duration for room in the queue, then respond with 503.
+    Block(Duration),
+}
+
+impl OverflowPolicy {
+    pub fn from_env() -> Result<Self, String> {
+        let policy = env::var("METLO_INGEST_OVERFLOW_POLICY").unwrap_or("block".to_string());
+        match policy.as_str() {
+            "drop" => Ok(OverflowPolicy::Drop),
+            "reject" => Ok(OverflowPolicy::Reject),
+            "block" => {
+                let timeout_ms: u64 = env::var("METLO_INGEST_BLOCK_TIMEOUT_MS")
+                    .unwrap_or(BLOCK_TIMEOUT_MS_DEFAULT.to_string())
+                    .parse()
+                    .unwrap_or(BLOCK_TIMEOUT_MS_DEFAULT);
+                Ok(OverflowPolicy::Block(Duration::from_millis(timeout_ms)))
+            }
+            e => Err(format!(
+                "Invalid METLO_INGEST_OVERFLOW_POLICY \"{}\", expected one of drop, reject, block",
+                e
+            )),
+        }
+    }
+}
+
+/// A report waiting in the ingest queue. Untagged so spool files written
+/// before typed reports existed still read back as `Csp`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IngestItem {
+    Csp(Box<BufferItem>),
+    Typed(TypedReport),
+}
+
+fn split_items(items: Vec<IngestItem>) -> (Vec<BufferItem>, Vec<TypedReport>) {
+    let mut buffer_items = vec![];
+    let mut typed_reports = vec![];
+    for item in items {
+        match item {
+            IngestItem::Csp(e) => buffer_items.push(*e),
+            IngestItem::Typed(e) => typed_reports.push(e),
+        }
+    }
+    (buffer_items, typed_reports)
+}
+
+/// Writes reports to their tables in a single transaction, so a failed write
+/// leaves none of them behind.
+pub fn append_reports(
+    state: &AppState,
+    buffer_items: &[BufferItem],
+    typed_reports: &[TypedReport],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if buffer_items.is_empty() && typed_reports.is_empty() {
+        return Ok(());
+    }
+    let mut conn = state.duckdb_pool.get()?;
+    let tx = conn.transaction()?;
+    if !buffer_items.is_empty() {
+        append_buffer_items(&tx, buffer_items)?;
+    }
+    if !typed_reports.is_empty() {
+        append_typed_reports(&tx, typed_reports)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Writes reports to their tables.
+pub fn append_ingest_items(
+    state: AppState,
+    items: Vec<IngestItem>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (buffer_items, typed_reports) = split_items(items);
+    append_reports(&state, &buffer_items, &typed_reports)
+}
+
+/// Reports taken off the queue that aren't in DuckDB yet, kept for the next
+/// flush when writing them fails, along with the spool segments that can go
+/// once they are stored.
+#[derive(Default)]
+struct PendingWrite {
+    buffer_items: Vec<BufferItem>,
+    typed_reports: Vec<TypedReport>,
+    segments: Vec<PathBuf>,
+}
+
+/// A bounded queue between the report handler and the DuckDB flusher.
+#[derive(Clone)]
+pub struct IngestQueue {
+    sender: mpsc::Sender<IngestItem>,
+    receiver: Arc<Mutex<mpsc::Receiver<IngestItem>>>,
+    spool: Arc<Mutex<Spool>>,
+    sampler: Arc<std::sync::Mutex<Sampler>>,
+    overflow_policy: OverflowPolicy,
+    metrics: Arc<Metrics>,
+    /// Held while reports are written to DuckDB.
+    writer: Arc<Mutex<()>>,
+    pending: Arc<Mutex<PendingWrite>>,
+}
+
+impl IngestQueue {
+    pub fn from_env(
+        metrics: Arc<Metrics>,
+        spool_dir: &Path,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let queue_size: usize = env::var("METLO_INGEST_QUEUE_SIZE")
+            .unwrap_or(QUEUE_SIZE_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(QUEUE_SIZE_DEFAULT);
+        Ok(IngestQueue::new(
+            metrics,
+            Spool::open(spool_dir)?,
+            Sampler::from_env(),
+            queue_size,
+            OverflowPolicy::from_env()?,
+        ))
+    }
+
+    pub fn new(
+        metrics: Arc<Metrics>,
+        spool: Spool,
+        sampler: Sampler,
+        queue_size: usize,
+        overflow_policy: OverflowPolicy,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(queue_size.max(1));
+        IngestQueue {
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            spool: Arc::new(Mutex::new(spool)),
+            sampler: Arc::new(std::sync::Mutex::new(sampler)),
+            overflow_policy,
+            metrics,
+            writer: Arc::new(Mutex::new(())),
+            pending: Arc::new(Mutex::new(PendingWrite::default())),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.sender.max_capacity()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Queues reports for the flusher. Each queued report is also written to the
+    /// spool before this returns, so it survives a crash once acknowledged.
+    ///
+    /// A batch is queued whole or not at all unless the overflow policy is
+    /// `Drop`, so a sender retrying a rejected batch doesn't store any of it twice.
+    /// A batch that couldn't fit even in an empty queue gets a 413.
+    pub async fn push(&self, items: Vec<IngestItem>) -> Result<(), (StatusCode, String)> {
+        let total = items.len() as u64;
+        let res = self.push_batch(items).await;
+        let accepted = *res.as_ref().unwrap_or(&0);
+        self.metrics
+            .reports_accepted
+            .fetch_add(accepted, Ordering::Relaxed);
+        self.metrics
+            .reports_dropped
+            .fetch_add(total - accepted, Ordering::Relaxed);
+        res.map(|_| ())
+    }
+
+    /// Returns how many of `items` were queued.
+    async fn push_batch(&self, items: Vec<IngestItem>) -> Result<u64, (StatusCode, String)> {
+        if items.is_empty() {
+            return Ok(0);
+        }
+        let lines = items
+            .iter()
+            .map(serde_json::to_vec)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(internal_error)?;
+        let full = || {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Report queue is full".to_string(),
+            )
+        };
+        if !matches!(self.overflow_policy, OverflowPolicy::Drop) && items.len() > self.capacity() {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("A batch can hold at most {} reports", self.capacity()),
+            ));
+        }
+        // Room is reserved before the spool lock is taken, as the flusher needs
+        // that lock to free any. A whole batch reserves its room in one go, so
+        // two batches can't each take part of it and both fail.
+        let permits: Vec<mpsc::Permit<'_, IngestItem>> = match self.overflow_policy {
+            OverflowPolicy::Drop => (0..items.len())
+                .map_while(|_| self.sender.try_reserve().ok())
+                .collect(),
+            OverflowPolicy::Reject => self
+                .sender
+                .try_reserve_many(items.len())
+                .map_err(|_| full())?
+                .collect(),
+            OverflowPolicy::Block(timeout) => {
+                match tokio::time::timeout(timeout, self.sender.reserve_many(items.len())).await {
+                    Ok(Ok(permits)) => permits.collect(),
+                    Ok(Err(_)) | Err(_) => return Err(full()),
+                }
+            }
+        };
+        let queued: Vec<_> = permits
+            .into_iter()
+            .zip(items.into_iter().zip(lines))
+            .map(|(permit, (item, line))| (permit, item, line))
+            .collect();
+        // Holding the spool lock while sending keeps the queue and the active
+        // spool file in step with each other.
+        let mut spool = self.spool.lock().await;
+        let lines: Vec<&[u8]> = queued.iter().map(|(_, _, line)| line.as_slice()).collect();
+        if let Err(e) = spool.append(&lines) {
+            error!("Error writing reports to spool: {}", e);
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Error writing reports to spool".to_string(),
+            ));
+        }
+        let accepted = queued.len() as u64;
+        for (permit, item, _) in queued {
+            permit.send(item);
+        }
+        Ok(accepted)
+    }
+
+    /// Holds off the flusher, for work that can't run alongside appends. Reports
+    /// keep queueing up meanwhile. Only call this outside the async runtime.
+    pub fn block_writes(&self) -> MutexGuard<'_, ()> {
+        self.writer.blocking_lock()
+    }
+
+    /// Takes every queued report along with the spool segment that holds them.
+    async fn drain(&self) -> std::io::Result<Option<(Vec<IngestItem>, PathBuf)>> {
+        let mut spool = self.spool.lock().await;
+        let mut receiver = self.receiver.lock().await;
+        let mut items = vec![];
+        while let Ok(item) = receiver.try_recv() {
+            items.push(item);
+        }
+        if items.is_empty() {
+            return Ok(None);
+        }
+        let segment = spool.rotate()?;
+        Ok(Some((items, segment)))
+    }
+
+    /// Runs drained reports through the sampler into `pending`. Held rows of
+    /// finished hours, or all of them when `release_all` is set, are released
+    /// along the way.
+    fn sample(&self, items: Vec<IngestItem>, release_all: bool, pending: &mut PendingWrite) {
+        let (buffer_items, typed_reports) = split_items(items);
+        pending.typed_reports.extend(typed_reports);
+        let mut sampler = self.sampler.lock().unwrap();
+        let mut skipped = 0;
+        pending
+            .buffer_items
+            .extend(sampler.sample(buffer_items, &mut skipped));
+        pending
+            .buffer_items
+            .extend(sampler.release_held(release_all));
+        self.metrics
+            .reports_sampled_out
+            .fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    /// Saves the sampler's held rows so they outlive the spool segments their
+    /// reports came from.
+    async fn save_held(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let spool = self.spool.lock().await;
+        let lines = {
+            let sampler = self.sampler.lock().unwrap();
+            // `IngestItem` is untagged, so a `BufferItem` reads back as `Csp`.
+            sampler
+                .held()
+                .map(serde_json::to_vec)
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        spool.save_held(&lines)?;
+        Ok(())
+    }
+}
+
+/// Writes everything currently queued to DuckDB. Reports that fail to write
+/// are retried by the next flush. Spool segments are only removed, and the
+/// sampler's held rows only saved, once every report drained before them is
+/// stored, so a replay on startup doesn't miss any. Delivery is at least once:
+/// a crash after the reports are committed but before their segments are
+/// removed stores them, and their rollup counts, a second time on replay.
+/// `release_all` writes the held rows out too on shutdown.
+pub async fn flush(state: &AppState, release_all: bool) {
+    let _writer = state.ingest.writer.lock().await;
+    let mut pending = state.ingest.pending.lock().await;
+    let items = match state.ingest.drain().await {
+        Ok(Some((items, segment))) => {
+            pending.segments.push(segment);
+            items
+        }
+        Ok(None) => vec![],
+        Err(e) => {
+            error!("Error rotating report spool: {}", e);
+            return;
+        }
+    };
+    state.ingest.sample(items, release_all, &mut pending);
+    if let Err(e) = append_reports(state, &pending.buffer_items, &pending.typed_reports) {
+        error!("Error appending reports, retrying on the next flush: {}", e);
+        return;
+    }
+    pending.buffer_items.clear();
+    pending.typed_reports.clear();
+    if let Err(e) = state.ingest.save_held().await {
+        error!("Error saving held sample rows: {}", e);
+        return;
+    }
+    for segment in pending.segments.drain(..) {
+        if let Err(e) = fs::remove_file(&segment) {
+            error!("Error removing spool segment {:?}: {}", segment, e);
+        }
+    }
+}
+
+/// Flushes every `FLUSH_INTERVAL` until `shutdown` fires. A flush already
+/// running is finished first, so no batch is cut off halfway.
+pub async fn run_flusher(state: AppState, mut shutdown: watch::Receiver<()>) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => flush(&state, false).await,
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{app_state, TempDir};
+
+    fn queue(dir: &TempDir, queue_size: usize, overflow_policy: OverflowPolicy) -> IngestQueue {
+        IngestQueue::new(
+            Arc::new(Metrics::default()),
+            Spool::open(dir.path()).unwrap(),
+            Sampler::new(0, 1),
+            queue_size,
+            overflow_policy,
+        )
+    }
+
+    fn reports(document_uris: &[&str]) -> Vec<IngestItem> {
+        document_uris
+            .iter()
+            .map(|e| {
+                IngestItem::Csp(Box::new(BufferItem {
+                    document_uri: e.to_string(),
+                    created_at: "2024-01-01T00:00:00.000Z".to_string(),
+                    sample_weight: 1,
+                    ..Default::default()
+                }))
+            })
+            .collect()
+    }
+
+    fn document_uris(items: &[IngestItem]) -> Vec<&str> {
+        items
+            .iter()
+            .map(|e| match e {
+                IngestItem::Csp(e) => e.document_uri.as_str(),
+                IngestItem::Typed(e) => e.url.as_str(),
+            })
+            .collect()
+    }
+
+    fn counts(queue: &IngestQueue) -> (u64, u64) {
+        (
+            queue.metrics.reports_accepted.load(Ordering::Relaxed),
+            queue.metrics.reports_dropped.load(Ordering::Relaxed),
+        )
+    }
+
+    #[tokio::test]
+    async fn drop_acknowledges_reports_past_capacity() {
+        let dir = TempDir::new();
+        let queue = queue(&dir, 1, OverflowPolicy::Drop);
+        queue.push(reports(&["a"])).await.unwrap();
+        queue.push(reports(&["b"])).await.unwrap();
+        assert_eq!(queue.depth(), 1);
+        assert_eq!(counts(&queue), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn reject_responds_with_503_when_full() {
+        let dir = TempDir::new();
+        let queue = queue(&dir, 1, OverflowPolicy::Reject);
+        queue.push(reports(&["a"])).await.unwrap();
+        let (status, _) = queue.push(reports(&["b"])).await.unwrap_err();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(queue.depth(), 1);
+        assert_eq!(counts(&queue), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn block_gives_up_after_its_timeout() {
+        let dir = TempDir::new();
+        let queue = queue(&dir, 1, OverflowPolicy::Block(Duration::from_millis(10)));
+        queue.push(reports(&["a"])).await.unwrap();
+        let (status, _) = queue.push(reports(&["b"])).await.unwrap_err();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(counts(&queue), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let dir = TempDir::new();
+        let queue = queue(&dir, 1, OverflowPolicy::Block(Duration::from_secs(10)));
+        queue.push(reports(&["a"])).await.unwrap();
+        let pushed = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(reports(&["b"])).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (items, _) = queue.drain().await.unwrap().unwrap();
+        assert_eq!(document_uris(&items), vec!["a"]);
+        pushed.await.unwrap().unwrap();
+        assert_eq!(queue.depth(), 1);
+        assert_eq!(counts(&queue), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn batches_are_queued_whole_or_not_at_all() {
+        for overflow_policy in [
+            OverflowPolicy::Reject,
+            OverflowPolicy::Block(Duration::from_millis(10)),
+        ] {
+            let dir = TempDir::new();
+            let queue = queue(&dir, 3, overflow_policy);
+            queue.push(reports(&["a", "b"])).await.unwrap();
+            let (status, _) = queue.push(reports(&["c", "d"])).await.unwrap_err();
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(queue.depth(), 2);
+            assert_eq!(counts(&queue), (2, 2));
+
+            let (items, _) = queue.drain().await.unwrap().unwrap();
+            assert_eq!(document_uris(&items), vec!["a", "b"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_larger_than_the_queue_are_too_large() {
+        let dir = TempDir::new();
+        let rejecting = queue(&dir, 2, OverflowPolicy::Reject);
+        let (status, _) = rejecting.push(reports(&["a", "b", "c"])).await.unwrap_err();
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(rejecting.depth(), 0);
+
+        // Dropping keeps what fits instead.
+        let dir = TempDir::new();
+        let dropping = queue(&dir, 2, OverflowPolicy::Drop);
+        dropping.push(reports(&["a", "b", "c"])).await.unwrap();
+        assert_eq!(dropping.depth(), 2);
+        assert_eq!(counts(&dropping), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn blocked_batches_wait_for_room_for_all_their_reports() {
+        let dir = TempDir::new();
+        let queue = queue(&dir, 3, OverflowPolicy::Block(Duration::from_secs(10)));
+        queue.push(reports(&["a", "b"])).await.unwrap();
+        let pushed = [reports(&["c", "d"]), reports(&["e", "f"])].map(|batch| {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.push(batch).await })
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // Neither batch fits next to the queued reports.
+        assert!(pushed.iter().all(|e| !e.is_finished()));
+
+        let mut drained = vec![];
+        for pushed in pushed {
+            while !pushed.is_finished() {
+                if let Some((items, _)) = queue.drain().await.unwrap() {
+                    drained.extend(items);
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            pushed.await.unwrap().unwrap();
+        }
+        drained.extend(queue.drain().await.unwrap().unwrap().0);
+        let mut uris = document_uris(&drained);
+        uris.sort();
+        assert_eq!(uris, vec!["a", "b", "c", "d", "e", "f"]);
+        assert_eq!(counts(&queue), (6, 0));
+    }
+
+    #[tokio::test]
+    async fn draining_a_full_queue_makes_room() {
+        let dir = TempDir::new();
+        let queue = queue(&dir, 2, OverflowPolicy::Reject);
+        queue.push(reports(&["a"])).await.unwrap();
+        queue.push(reports(&["b"])).await.unwrap();
+        assert!(queue.push(reports(&["c"])).await.is_err());
+
+        let (items, segment) = queue.drain().await.unwrap().unwrap();
+        assert_eq!(document_uris(&items), vec!["a", "b"]);
+        assert_eq!(queue.depth(), 0);
+        // The segment holds exactly the drained reports.
+        assert_eq!(fs::read_to_string(&segment).unwrap().lines().count(), 2);
+        assert!(queue.drain().await.unwrap().is_none());
+
+        queue.push(reports(&["c"])).await.unwrap();
+        assert_eq!(queue.depth(), 1);
+        assert_eq!(counts(&queue), (3, 1));
+    }
+
+    fn segments(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("reports.spool.")
+            })
+            .count()
+    }
+
+    fn stored_document_uris(state: &AppState) -> Vec<String> {
+        let conn = state.duckdb_pool.get().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT document_uri FROM csp_report ORDER BY 1")
+            .unwrap();
+        let rows = stmt
+            .query_map([], |e| e.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        rows
+    }
+
+    #[tokio::test]
+    async fn failed_flushes_are_retried_once() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        let rename = |from: &str, to: &str| {
+            state
+                .duckdb_pool
+                .get()
+                .unwrap()
+                .execute_batch(&format!("ALTER TABLE {} RENAME TO {};", from, to))
+                .unwrap();
+        };
+
+        state.ingest.push(reports(&["a", "b"])).await.unwrap();
+        rename("csp_report", "csp_report_gone");
+        flush(&state, false).await;
+        // The segment stays on disk and the reports in memory.
+        assert_eq!(segments(dir.path()), 1);
+        assert_eq!(state.ingest.pending.lock().await.buffer_items.len(), 2);
+
+        rename("csp_report_gone", "csp_report");
+        state.ingest.push(reports(&["c"])).await.unwrap();
+        flush(&state, false).await;
+        assert_eq!(segments(dir.path()), 0);
+        assert!(state.ingest.pending.lock().await.buffer_items.is_empty());
+        assert_eq!(stored_document_uris(&state), vec!["a", "b", "c"]);
+
+        flush(&state, false).await;
+        assert_eq!(stored_document_uris(&state), vec!["a", "b", "c"]);
+        let rollup_count: u64 = state
+            .duckdb_pool
+            .get()
+            .unwrap()
+            .query_row("SELECT SUM(cnt) FROM csp_report_rollup", [], |e| e.get(0))
+            .unwrap();
+        assert_eq!(rollup_count, 3);
+    }
+}
@@ -0,0 +1,230 @@
+use std::{env, fmt, net::SocketAddr, path::PathBuf};
+
+use axum::{extract::connect_info::Connected, Router};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use hyper::server::conn::AddrStream;
+use log::{error, info};
+use tokio::{sync::watch, task::JoinHandle};
+
+#[cfg(unix)]
+use hyper::server::accept::Accept;
+#[cfg(unix)]
+use std::{
+    fs, io,
+    os::unix::fs::FileTypeExt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// An address the service accepts connections on.
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parses a comma separated list of `host:port`, `[v6]:port` and
+/// `unix:/path/to.sock` addresses.
+pub fn parse_listen_addrs(value: &str) -> Result<Vec<ListenAddr>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|e| match e.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            _ => e
+                .parse::<SocketAddr>()
+                .map(ListenAddr::Tcp)
+                .map_err(|_| format!("Invalid listen address: {}", e)),
+        })
+        .collect()
+}
+
+/// `METLO_LISTEN`, or every IPv4 interface on `METLO_PORT` when unset.
+pub fn listen_addrs_from_env() -> Result<Vec<ListenAddr>, String> {
+    if let Ok(value) = env::var("METLO_LISTEN") {
+        let addrs = parse_listen_addrs(&value)?;
+        if !addrs.is_empty() {
+            return Ok(addrs);
+        }
+    }
+    let port: u16 = env::var("METLO_PORT")
+        .unwrap_or("8080".to_string())
+        .parse()
+        .unwrap_or(8080);
+    Ok(vec![ListenAddr::Tcp(SocketAddr::from((
+        [0, 0, 0, 0],
+        port,
+    )))])
+}
+
+/// The peer of a connection. Unix socket peers have no address.
+#[derive(Debug, Clone, Copy)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl Connected<&AddrStream> for PeerAddr {
+    fn connect_info(target: &AddrStream) -> Self {
+        PeerAddr::Tcp(target.remote_addr())
+    }
+}
+
+/// Used by the TLS server, which hands over the peer address directly.
+impl Connected<SocketAddr> for PeerAddr {
+    fn connect_info(target: SocketAddr) -> Self {
+        PeerAddr::Tcp(target)
+    }
+}
+
+#[cfg(unix)]
+impl Connected<&UnixStream> for PeerAddr {
+    fn connect_info(_: &UnixStream) -> Self {
+        PeerAddr::Unix
+    }
+}
+
+#[cfg(unix)]
+struct UnixAccept(UnixListener);
+
+#[cfg(unix)]
+impl Accept for UnixAccept {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|res| Some(res.map(|(stream, _)| stream)))
+    }
+}
+
+/// Binds every address and serves `app` on each until `shutdown` changes.
+/// Binding happens up front so a bad address fails startup. With `tls` set,
+/// TCP listeners serve HTTPS only.
+pub fn serve(
+    app: Router,
+    addrs: &[ListenAddr],
+    tls: Option<RustlsConfig>,
+    shutdown: watch::Receiver<()>,
+) -> Result<Vec<JoinHandle<()>>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut servers = vec![];
+    for addr in addrs {
+        let mut shutdown = shutdown.clone();
+        let graceful = async move {
+            shutdown.changed().await.ok();
+        };
+        let service = app
+            .clone()
+            .into_make_service_with_connect_info::<PeerAddr>();
+        let server: JoinHandle<()> = match addr {
+            ListenAddr::Tcp(socket_addr) if tls.is_some() => {
+                let listener = std::net::TcpListener::bind(socket_addr)
+                    .map_err(|e| format!("Error binding {}: {}", addr, e))?;
+                listener.set_nonblocking(true)?;
+                let handle = Handle::new();
+                tokio::task::spawn({
+                    let handle = handle.clone();
+                    async move {
+                        graceful.await;
+                        handle.graceful_shutdown(None);
+                    }
+                });
+                let server = axum_server::from_tcp_rustls(listener, tls.clone().unwrap())
+                    .handle(handle)
+                    .serve(service);
+                let addr = addr.clone();
+                tokio::task::spawn(async move {
+                    if let Err(e) = server.await {
+                        error!("Server at {} failed: {}", addr, e);
+                    }
+                })
+            }
+            ListenAddr::Tcp(socket_addr) => {
+                let server = axum::Server::try_bind(socket_addr)
+                    .map_err(|e| format!("Error binding {}: {}", addr, e))?
+                    .serve(service)
+                    .with_graceful_shutdown(graceful);
+                let addr = addr.clone();
+                tokio::task::spawn(async move {
+                    if let Err(e) = server.await {
+                        error!("Server at {} failed: {}", addr, e);
+                    }
+                })
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // A socket left behind by an unclean exit would fail the bind.
+                if fs::metadata(path).is_ok_and(|e| e.file_type().is_socket()) {
+                    fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)
+                    .map_err(|e| format!("Error binding {}: {}", addr, e))?;
+                let server = axum::Server::builder(UnixAccept(listener))
+                    .serve(service)
+                    .with_graceful_shutdown(graceful);
+                let addr = addr.clone();
+                let path = path.clone();
+                tokio::task::spawn(async move {
+                    if let Err(e) = server.await {
+                        error!("Server at {} failed: {}", addr, e);
+                    }
+                    fs::remove_file(path).ok();
+                })
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => {
+                return Err(format!("Unix sockets aren't supported here: {}", addr).into())
+            }
+        };
+        info!("⚡️ Starting server at {}", addr);
+        servers.push(server);
+    }
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mixed_addresses() {
+        let addrs = parse_listen_addrs("127.0.0.1:8080, [::1]:8443,unix:/run/metlo.sock,").unwrap();
+        assert_eq!(addrs.len(), 3);
+        assert!(
+            matches!(addrs[0], ListenAddr::Tcp(a) if a == SocketAddr::from(([127, 0, 0, 1], 8080)))
+        );
+        assert!(matches!(addrs[1], ListenAddr::Tcp(a) if a.is_ipv6() && a.port() == 8443));
+        assert!(
+            matches!(&addrs[2], ListenAddr::Unix(path) if path == &PathBuf::from("/run/metlo.sock"))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        for value in [
+            "localhost",
+            "127.0.0.1",
+            "::1:8080",
+            "unix:",
+            "127.0.0.1:8080,nope",
+        ] {
+            assert!(parse_listen_addrs(value).is_err(), "{}", value);
+        }
+    }
+}
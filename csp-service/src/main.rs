@@ -1,96 +1,368 @@
 mod auth;
+mod client_ip;
+mod cors;
+mod export;
+mod filter;
+mod ingest;
+mod listen;
+mod metrics;
+mod migrations;
+mod nel;
+mod normalize;
 mod pages;
+mod payload;
+mod project;
+mod rate_limit;
+mod redact;
 mod report;
+mod report_types;
+mod reporting_headers;
+mod retention;
+mod rollup;
+mod sampler;
+mod spool;
 mod state;
+#[cfg(test)]
+mod test_utils;
+mod tls;
 mod token;
+mod user_agent;
 mod utils;
 
-use log::{error, info};
-use std::{env, net::SocketAddr, time::Duration};
+use log::info;
+use std::env;
 
 use axum::{
+    extract::DefaultBodyLimit,
+    handler::Handler,
+    http::StatusCode,
     middleware,
-    routing::{delete, get, post},
+    routing::{delete, get, post, MethodRouter},
     Router,
 };
 use dotenv::dotenv;
-use lazy_static::lazy_static;
-use report::{append_buffer_items, BufferItem};
-use tokio::sync::RwLock;
+use tokio::{signal, sync::watch};
 
 const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
 
-lazy_static! {
-    pub static ref REPORT_BUFFER: RwLock<Vec<BufferItem>> = RwLock::new(vec![]);
-}
-
 async fn health() -> &'static str {
     "OK"
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    dotenv().ok();
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
 
-    let log_level = match env::var("METLO_LOG_LEVEL") {
-        Ok(s) if LOG_LEVELS.contains(&s.as_str()) => s,
-        _ => "info".to_owned(),
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
     };
-    env::set_var("RUST_LOG", log_level);
-    env_logger::init();
 
-    let app_state = state::AppState::make_app_state().await?;
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down, waiting for in-flight requests");
+}
 
-    let app_state_appender = app_state.clone();
-    tokio::task::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(1));
-        loop {
-            interval.tick().await;
-            let mut buf_write = REPORT_BUFFER.write().await;
-            let buffer_items: Vec<BufferItem> = buf_write.drain(..).collect();
-            drop(buf_write);
-            if let Err(e) = append_buffer_items(app_state_appender.clone(), buffer_items) {
-                error!("Error appending buffer items: {}", e);
-            };
+/// One-off maintenance commands, run instead of the service.
+fn run_command(args: &[String]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match args[0].as_str() {
+        "rebuild-rollup" => {
+            let duckdb_pool = state::open_duckdb(&state::data_path()?)?;
+            let rows = rollup::rebuild(&mut *duckdb_pool.get()?)?;
+            info!(
+                "Rebuilt the distinct report rollup, {} distinct reports",
+                rows
+            );
+            Ok(())
         }
-    });
+        "export-parquet" => {
+            let usage = "Usage: export-parquet <PATH> [--from <TIME>] [--to <TIME>] \
+                [--project-id <ID>] [--partition-by-day]";
+            let mut path = None;
+            let mut params = export::ExportQueryParams::default();
+            let mut project = project::ProjectFilter { project_id: None };
+            let mut rest = args[1..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--from" => params.from = Some(rest.next().ok_or(usage)?.clone()),
+                    "--to" => params.to = Some(rest.next().ok_or(usage)?.clone()),
+                    "--project-id" => {
+                        let id = rest.next().ok_or(usage)?;
+                        project.project_id = Some(
+                            id.parse()
+                                .map_err(|_| format!("Invalid project id: {}", id))?,
+                        );
+                    }
+                    "--partition-by-day" => params.partition_by_day = true,
+                    e if path.is_none() && !e.starts_with("--") => path = Some(e),
+                    _ => return Err(usage.into()),
+                }
+            }
+            let path = path.ok_or(usage)?;
+            let duckdb_pool = state::open_duckdb(&state::data_path()?)?;
+            export::export_parquet(
+                &*duckdb_pool.get()?,
+                &params,
+                &project,
+                std::path::Path::new(path),
+            )?;
+            info!("Exported reports to {}", path);
+            Ok(())
+        }
+        e => Err(format!(
+            "Unknown command \"{}\", expected rebuild-rollup or export-parquet",
+            e
+        )
+        .into()),
+    }
+}
+
+/// Wraps a report handler with the body limit, rate limiting and CORS every
+/// ingest route shares.
+fn ingest_route<H, T>(
+    handler: H,
+    app_state: &state::AppState,
+) -> Result<MethodRouter<state::AppState>, String>
+where
+    H: Handler<T, state::AppState>,
+    T: 'static,
+{
+    Ok(post(handler)
+        .layer(DefaultBodyLimit::max(app_state.max_body_bytes))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            rate_limit::rate_limit_middleware,
+        ))
+        // Preflight requests are answered by the CORS layer.
+        .options(|| async { StatusCode::NO_CONTENT })
+        .layer(cors::ingest_cors_layer()?))
+}
 
-    let auth_routes = Router::new()
+/// The public router and, with `separate_admin`, the admin one. With a
+/// separate admin listener the public one only takes reports and answers
+/// health checks.
+fn build_routers(
+    app_state: &state::AppState,
+    separate_admin: bool,
+) -> Result<(Router, Option<Router>), String> {
+    let api_routes = Router::new()
         .route("/api/verify", get(health))
         .route("/api/reports", get(report::get_reports))
         .route("/api/tokens", get(token::get_tokens))
         .route("/api/token/:id", delete(token::delete_token))
+        .route(
+            "/api/projects",
+            get(project::get_projects).post(project::create_project),
+        )
+        .route("/api/project/:id", delete(project::delete_project))
+        .route(
+            "/api/reporting-headers",
+            get(reporting_headers::get_reporting_headers),
+        )
         .route("/api/distinct-reports", get(report::get_distinct_reports))
+        .route(
+            "/api/distinct-reports/:fingerprint",
+            get(report::get_distinct_report),
+        )
+        .route(
+            "/api/distinct-reports/:fingerprint/browsers",
+            get(report::get_browser_breakdown),
+        )
         .route(
             "/api/violation-count",
             get(report::get_violation_count_by_day),
         )
+        .route("/api/metrics", get(metrics::get_metrics))
+        .route("/api/retention", get(retention::get_retention))
+        .route("/api/export/parquet", get(export::get_parquet_export))
+        .route("/api/nel/error-rates", get(nel::get_error_rates))
+        .route(
+            "/api/report-types/:report_type/reports",
+            get(report_types::get_typed_reports),
+        )
+        .route(
+            "/api/report-types/:report_type/distinct-reports",
+            get(report_types::get_distinct_typed_reports),
+        )
+        .route(
+            "/api/report-types/:report_type/count",
+            get(report_types::get_typed_report_count_by_day),
+        )
         .route_layer(middleware::from_fn_with_state(
             app_state.clone(),
             auth::auth_middleware,
-        ));
-    let no_auth_routes = Router::new()
+        ))
         .route("/api", get(health))
-        .route("/api/gen-token", post(auth::new_token))
-        .route("/", post(report::report_csp))
-        .route("/", get(pages::index));
-
-    let app = Router::new()
-        .merge(auth_routes)
-        .merge(no_auth_routes)
-        .with_state(app_state);
-
-    let port: u16 = env::var("METLO_PORT")
-        .unwrap_or("8080".to_string())
-        .parse()
-        .unwrap_or(8080);
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    info!("⚡️ Starting server at {}", addr.to_string());
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+        .route("/api/gen-token", post(auth::new_token));
+    let api_routes = match cors::api_cors_layer()? {
+        Some(cors_layer) => api_routes.layer(cors_layer),
+        None => api_routes,
+    };
+    let ingest_routes = Router::new()
+        .route("/", ingest_route(report::report_csp, app_state)?)
+        .route(
+            "/r/:project_key",
+            ingest_route(report::report_project_csp, app_state)?,
+        );
+    let dashboard_routes = Router::new()
+        .route("/", get(pages::index))
+        .merge(api_routes);
+
+    let (app, admin_app) = if separate_admin {
+        (
+            ingest_routes.route("/api", get(health)),
+            Some(dashboard_routes.with_state(app_state.clone())),
+        )
+    } else {
+        (ingest_routes.merge(dashboard_routes), None)
+    };
+    Ok((app.with_state(app_state.clone()), admin_app))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    dotenv().ok();
+
+    let log_level = match env::var("METLO_LOG_LEVEL") {
+        Ok(s) if LOG_LEVELS.contains(&s.as_str()) => s,
+        _ => "info".to_owned(),
+    };
+    env::set_var("RUST_LOG", log_level);
+    env_logger::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        return run_command(&args);
+    }
+
+    let app_state = state::AppState::make_app_state().await?;
+
+    let (flusher_shutdown_tx, flusher_shutdown_rx) = watch::channel(());
+    let flusher = tokio::task::spawn(ingest::run_flusher(app_state.clone(), flusher_shutdown_rx));
+    tokio::task::spawn(rate_limit::run_pruner(app_state.clone()));
+    tokio::task::spawn(retention::run_pruner(app_state.clone()));
+
+    let listen_addrs = listen::listen_addrs_from_env()?;
+    let admin_listen_addrs =
+        listen::parse_listen_addrs(&env::var("METLO_ADMIN_LISTEN").unwrap_or_default())?;
+    let (app, admin_app) = build_routers(&app_state, !admin_listen_addrs.is_empty())?;
+
+    let tls = match tls::TlsFiles::from_env()? {
+        Some(files) => {
+            let config = files.load().await?;
+            tokio::task::spawn(tls::run_reloader(config.clone(), files));
+            Some(config)
+        }
+        None => None,
+    };
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let mut servers = listen::serve(app, &listen_addrs, tls.clone(), shutdown_rx.clone())?;
+    if let Some(admin_app) = admin_app {
+        servers.extend(listen::serve(
+            admin_app,
+            &admin_listen_addrs,
+            tls,
+            shutdown_rx,
+        )?);
+    }
+    shutdown_signal().await;
+    shutdown_tx.send(()).ok();
+    for server in servers {
+        server.await.ok();
+    }
+
+    // The flusher finishes the batch it is writing before it stops. Whatever
+    // is left gets written by the final flush.
+    flusher_shutdown_tx.send(()).ok();
+    flusher.await.ok();
+    ingest::flush(&app_state, true).await;
+    app_state.duckdb_pool.get()?.execute_batch("CHECKPOINT;")?;
+    info!("Flushed pending reports, exiting");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Method, Request},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_utils::{app_state, TempDir};
+
+    async fn status(app: &Router, method: Method, uri: &str) -> StatusCode {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/csp-report")
+            .body(Body::from(
+                r#"{"csp-report": {"document-uri": "https://example.com/", "blocked-uri": "inline"}}"#,
+            ))
+            .unwrap();
+        app.clone().oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn one_listener_serves_reports_and_the_api() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        let (app, admin_app) = build_routers(&state, false).unwrap();
+        assert!(admin_app.is_none());
+        assert_eq!(status(&app, Method::POST, "/").await, StatusCode::OK);
+        assert_eq!(status(&app, Method::GET, "/api").await, StatusCode::OK);
+        assert_eq!(
+            status(&app, Method::GET, "/api/reports").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_listener_takes_the_api_off_the_public_one() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        let (app, admin_app) = build_routers(&state, true).unwrap();
+        let admin_app = admin_app.unwrap();
+
+        assert_eq!(status(&app, Method::POST, "/").await, StatusCode::OK);
+        assert_eq!(
+            status(&app, Method::POST, "/r/unknown").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status(&app, Method::GET, "/api").await, StatusCode::OK);
+        for uri in ["/api/reports", "/api/projects", "/api/gen-token"] {
+            assert_eq!(
+                status(&app, Method::GET, uri).await,
+                StatusCode::NOT_FOUND,
+                "{}",
+                uri
+            );
+        }
+
+        assert_eq!(
+            status(&admin_app, Method::GET, "/api/reports").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&admin_app, Method::GET, "/api").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&admin_app, Method::POST, "/r/unknown").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{extract::State, Json};
+use serde::Serialize;
+
+use crate::{filter::FilterCount, state::AppState};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub reports_accepted: AtomicU64,
+    pub reports_dropped: AtomicU64,
+    pub requests_rate_limited: AtomicU64,
+    pub reports_sampled_out: AtomicU64,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSnapshot {
+    pub reports_accepted: u64,
+    pub reports_dropped: u64,
+    pub requests_rate_limited: u64,
+    pub reports_filtered: Vec<FilterCount>,
+    pub reports_sampled_out: u64,
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+}
+
+pub async fn get_metrics(State(state): State<AppState>) -> Json<MetricsSnapshot> {
+    Json(MetricsSnapshot {
+        reports_accepted: state.metrics.reports_accepted.load(Ordering::Relaxed),
+        reports_dropped: state.metrics.reports_dropped.load(Ordering::Relaxed),
+        requests_rate_limited: state.metrics.requests_rate_limited.load(Ordering::Relaxed),
+        reports_filtered: state.noise_filter.counts(),
+        reports_sampled_out: state.metrics.reports_sampled_out.load(Ordering::Relaxed),
+        queue_depth: state.ingest.depth(),
+        queue_capacity: state.ingest.capacity(),
+    })
+}
@@ -0,0 +1,277 @@
+use deadpool_sqlite::rusqlite;
+use log::info;
+
+/// A schema change, applied once in `version` order.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Scripts must tolerate databases created before migrations existed, whose
+/// tables were made with `CREATE TABLE IF NOT EXISTS` by whichever release
+/// first ran against them.
+const DUCKDB_MIGRATIONS: [Migration; 10] = [
+    Migration {
+        version: 1,
+        name: "baseline",
+        sql: include_str!("../migrations/duckdb/0001_baseline.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "user_agent",
+        sql: include_str!("../migrations/duckdb/0002_user_agent.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "sample_weight",
+        sql: include_str!("../migrations/duckdb/0003_sample_weight.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "report_types",
+        sql: include_str!("../migrations/duckdb/0004_report_types.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "nel_report",
+        sql: include_str!("../migrations/duckdb/0005_nel_report.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "project_id",
+        sql: include_str!("../migrations/duckdb/0006_project_id.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "blocked_uri_raw",
+        sql: include_str!("../migrations/duckdb/0007_blocked_uri_raw.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "redacted",
+        sql: include_str!("../migrations/duckdb/0008_redacted.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "csp_report_rollup",
+        sql: include_str!("../migrations/duckdb/0009_csp_report_rollup.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "fingerprint",
+        sql: include_str!("../migrations/duckdb/0010_fingerprint.sql"),
+    },
+];
+
+const SQLITE_MIGRATIONS: [Migration; 2] = [
+    Migration {
+        version: 1,
+        name: "api_token",
+        sql: include_str!("../migrations/sqlite/0001_api_token.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "project",
+        sql: include_str!("../migrations/sqlite/0002_project.sql"),
+    },
+];
+
+const SCHEMA_VERSION_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_version (
+    version INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    applied_at TEXT NOT NULL
+);";
+
+/// Returns the migrations newer than `current`, refusing to go on if the
+/// database was written by a newer release.
+fn pending<'a>(
+    db: &str,
+    migrations: &'a [Migration],
+    current: u32,
+) -> Result<&'a [Migration], String> {
+    let latest = migrations.last().map_or(0, |e| e.version);
+    if current > latest {
+        return Err(format!(
+            "The {} database is at schema version {}, newer than the {} this release supports. Upgrade the service.",
+            db, current, latest
+        ));
+    }
+    Ok(&migrations[migrations.partition_point(|e| e.version <= current)..])
+}
+
+fn applied_at() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+pub fn migrate_duckdb(
+    conn: &mut duckdb::Connection,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    conn.execute_batch(SCHEMA_VERSION_TABLE)?;
+    let current: u32 = conn.query_row(
+        "SELECT CAST(COALESCE(MAX(version), 0) AS UINTEGER) FROM schema_version",
+        [],
+        |e| e.get(0),
+    )?;
+    for migration in pending("DuckDB", &DUCKDB_MIGRATIONS, current)? {
+        info!(
+            "Migrating DuckDB to version {} ({})",
+            migration.version, migration.name
+        );
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)
+            .map_err(|e| format!("Error running migration {}: {}", migration.name, e))?;
+        tx.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?, ?, ?)",
+            duckdb::params![migration.version, migration.name, applied_at()],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+pub fn migrate_sqlite(conn: &mut rusqlite::Connection) -> Result<(), String> {
+    conn.execute_batch(SCHEMA_VERSION_TABLE)
+        .map_err(|e| format!("Error creating schema_version table: {}", e))?;
+    let current: u32 = conn
+        .query_row(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+            [],
+            |e| e.get(0),
+        )
+        .map_err(|e| format!("Error reading schema version: {}", e))?;
+    for migration in pending("SQLite", &SQLITE_MIGRATIONS, current)? {
+        info!(
+            "Migrating SQLite to version {} ({})",
+            migration.version, migration.name
+        );
+        let run = |conn: &mut rusqlite::Connection| -> rusqlite::Result<()> {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration.sql)?;
+            tx.execute(
+                "INSERT INTO schema_version (version, name, applied_at) VALUES (?, ?, ?)",
+                rusqlite::params![migration.version, migration.name, applied_at()],
+            )?;
+            tx.commit()
+        };
+        run(conn).map_err(|e| format!("Error running migration {}: {}", migration.name, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema_version(conn: &duckdb::Connection) -> u32 {
+        conn.query_row(
+            "SELECT CAST(MAX(version) AS UINTEGER) FROM schema_version",
+            [],
+            |e| e.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn upgrades_a_database_from_before_migrations() {
+        let mut conn = duckdb::Connection::open_in_memory().unwrap();
+        // The table as the first release created it, without `schema_version`.
+        conn.execute_batch(
+            r"CREATE SEQUENCE IF NOT EXISTS csp_report_seq;
+              CREATE TABLE IF NOT EXISTS csp_report (
+                source_ip TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT NOW(),
+                document_uri TEXT NOT NULL,
+                referrer TEXT NOT NULL,
+                violated_directive TEXT NOT NULL,
+                effective_directive TEXT NOT NULL,
+                original_policy TEXT NOT NULL,
+                disposition TEXT NOT NULL,
+                blocked_uri TEXT,
+                line_number UINTEGER,
+                column_number UINTEGER,
+                source_file TEXT,
+                status_code UINTEGER,
+                script_sample TEXT NOT NULL,
+              );
+              INSERT INTO csp_report (source_ip, document_uri, referrer, violated_directive,
+                effective_directive, original_policy, disposition, blocked_uri, line_number,
+                column_number, source_file, status_code, script_sample)
+              VALUES ('10.0.0.1', 'https://example.com/', '', 'script-src', 'script-src',
+                'script-src ''self''', 'enforce', 'https://cdn.example.com/a.js', 3, 7,
+                'https://example.com/app.js', 200, '');",
+        )
+        .unwrap();
+
+        migrate_duckdb(&mut conn).unwrap();
+
+        assert_eq!(
+            schema_version(&conn),
+            DUCKDB_MIGRATIONS.last().unwrap().version
+        );
+        let row: (String, String, Option<String>, u32, u32, String) = conn
+            .query_row(
+                "SELECT source_ip, document_uri, blocked_uri, line_number, sample_weight,
+                    browser_family
+                 FROM csp_report",
+                [],
+                |e| {
+                    Ok((
+                        e.get(0)?,
+                        e.get(1)?,
+                        e.get(2)?,
+                        e.get(3)?,
+                        e.get(4)?,
+                        e.get(5)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            row,
+            (
+                "10.0.0.1".to_string(),
+                "https://example.com/".to_string(),
+                Some("https://cdn.example.com/a.js".to_string()),
+                3,
+                1,
+                "UNKNOWN".to_string()
+            )
+        );
+
+        // Running again is a no-op.
+        migrate_duckdb(&mut conn).unwrap();
+        let applied: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |e| e.get(0))
+            .unwrap();
+        assert_eq!(applied, DUCKDB_MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn refuses_a_newer_duckdb_schema() {
+        let mut conn = duckdb::Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA_VERSION_TABLE).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?, 'future', '')",
+            [DUCKDB_MIGRATIONS.last().unwrap().version + 1],
+        )
+        .unwrap();
+
+        let err = migrate_duckdb(&mut conn).unwrap_err();
+        assert!(err.to_string().contains("Upgrade the service"), "{}", err);
+    }
+
+    #[test]
+    fn refuses_a_newer_sqlite_schema() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        migrate_sqlite(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?, 'future', '')",
+            [SQLITE_MIGRATIONS.last().unwrap().version + 1],
+        )
+        .unwrap();
+
+        let err = migrate_sqlite(&mut conn).unwrap_err();
+        assert!(err.contains("Upgrade the service"), "{}", err);
+    }
+}
@@ -0,0 +1,307 @@
+use axum::{
+    extract::{self, State},
+    http::StatusCode,
+    Json,
+};
+use duckdb::{params_from_iter, ToSql};
+use serde::{Deserialize, Serialize};
+
+use crate::{project::ProjectFilter, state::AppState, utils::internal_error};
+
+/// Each NEL report stands for `1 / sampling_fraction` requests, a missing or
+/// zero fraction counts as a single request.
+const NEL_WEIGHT: &str = "1.0 / COALESCE(NULLIF(sampling_fraction, 0), 1)";
+
+/// The origin of the request that failed, taken from the report's `url`.
+const ORIGIN: &str = r"regexp_extract(url, '^[a-zA-Z][a-zA-Z0-9+.-]*://[^/?#]+')";
+
+const DAYS_DEFAULT: u32 = 7;
+/// Longer windows would take the start date out of DuckDB's date range.
+const DAYS_MAX: u32 = 36_500;
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorRateGroupBy {
+    #[default]
+    Type,
+    Phase,
+    Origin,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorRateInterval {
+    #[default]
+    Hour,
+    Day,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetErrorRateQueryParams {
+    #[serde(default)]
+    pub group_by: ErrorRateGroupBy,
+    #[serde(default)]
+    pub interval: ErrorRateInterval,
+    pub days: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorRate {
+    pub bucket: String,
+    pub key: Option<String>,
+    /// Estimated number of failed requests.
+    pub errors: f64,
+    /// Estimated number of requests the rate is relative to.
+    pub total: f64,
+    pub rate: f64,
+}
+
+/// Error rates from NEL reports per time bucket. Grouped by origin, the rate
+/// is the share of failed requests to that origin. Grouped by type or phase,
+/// it is the share of all requests in the bucket that failed with that type
+/// or in that phase, as successful (`ok`) reports all have the application
+/// phase.
+pub async fn get_error_rates(
+    State(state): State<AppState>,
+    extract::Query(query_params): extract::Query<GetErrorRateQueryParams>,
+    extract::Query(project): extract::Query<ProjectFilter>,
+) -> Result<Json<Vec<ErrorRate>>, (StatusCode, String)> {
+    if query_params.days.is_some_and(|e| e > DAYS_MAX) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("days can't be more than {}", DAYS_MAX),
+        ));
+    }
+    let conn = state.duckdb_pool.get().map_err(internal_error)?;
+    let res = query_error_rates(&conn, &query_params, &project).map_err(internal_error)?;
+    Ok(Json(res))
+}
+
+fn query_error_rates(
+    conn: &duckdb::Connection,
+    query_params: &GetErrorRateQueryParams,
+    project: &ProjectFilter,
+) -> Result<Vec<ErrorRate>, duckdb::Error> {
+    let key = match query_params.group_by {
+        ErrorRateGroupBy::Type => "nel_type",
+        ErrorRateGroupBy::Phase => "phase",
+        ErrorRateGroupBy::Origin => ORIGIN,
+    };
+    let interval = match query_params.interval {
+        ErrorRateInterval::Hour => "hour",
+        ErrorRateInterval::Day => "day",
+    };
+    let total = match query_params.group_by {
+        ErrorRateGroupBy::Origin => "SUM(weight)",
+        _ => "SUM(SUM(weight)) OVER (PARTITION BY bucket)",
+    };
+    let days = query_params.days.unwrap_or(DAYS_DEFAULT);
+
+    let mut params: Vec<&dyn ToSql> = vec![&days];
+    let query = format!(
+        "WITH weighted AS (
+            SELECT
+                date_trunc('{}', created_at) AS bucket,
+                {} AS key,
+                nel_type,
+                {} AS weight
+            FROM nel_report
+            WHERE created_at >= CURRENT_DATE - CAST(? AS INTEGER) AND {}
+        )
+        SELECT * FROM (
+            SELECT
+                CAST(bucket AS TEXT) AS bucket,
+                CAST(key AS TEXT) AS key,
+                SUM(CASE WHEN nel_type <> 'ok' THEN weight ELSE 0 END) AS errors,
+                {} AS total
+            FROM weighted
+            GROUP BY bucket, key
+        )
+        WHERE errors > 0
+        ORDER BY 1 ASC, 3 DESC",
+        interval,
+        key,
+        NEL_WEIGHT,
+        project.condition(&mut params),
+        total
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let res = stmt
+        .query_map(params_from_iter(params), |e| {
+            let errors: f64 = e.get(2)?;
+            let total: f64 = e.get(3)?;
+            Ok(ErrorRate {
+                bucket: e.get(0)?,
+                key: e.get(1)?,
+                errors,
+                total,
+                rate: if total > 0.0 { errors / total } else { 0.0 },
+            })
+        })?
+        .collect::<Result<Vec<ErrorRate>, duckdb::Error>>();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{app_state, migrated_duckdb, TempDir};
+
+    #[test]
+    fn reports_are_weighted_by_sampling_fraction() {
+        let conn = migrated_duckdb();
+        for fraction in [Some(0.25), Some(0.0), None] {
+            conn.execute(
+                "INSERT INTO nel_report (source_ip, url, user_agent, sampling_fraction)
+                VALUES ('', '', '', ?)",
+                [fraction],
+            )
+            .unwrap();
+        }
+        let weight: f64 = conn
+            .query_row(
+                &format!("SELECT SUM({}) FROM nel_report", NEL_WEIGHT),
+                [],
+                |e| e.get(0),
+            )
+            .unwrap();
+        assert_eq!(weight, 6.0);
+    }
+
+    #[test]
+    fn origins_are_taken_from_request_urls() {
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        let origin: String = conn
+            .query_row(
+                &format!("SELECT {} FROM (SELECT ? AS url)", ORIGIN),
+                ["https://example.com:8443/a?b#c"],
+                |e| e.get(0),
+            )
+            .unwrap();
+        assert_eq!(origin, "https://example.com:8443");
+    }
+
+    fn rates(
+        conn: &duckdb::Connection,
+        group_by: ErrorRateGroupBy,
+        project_id: Option<i64>,
+    ) -> Vec<(Option<String>, f64, f64, f64)> {
+        let query_params = GetErrorRateQueryParams {
+            group_by,
+            interval: ErrorRateInterval::Day,
+            days: None,
+        };
+        let rates = query_error_rates(conn, &query_params, &ProjectFilter { project_id }).unwrap();
+        // Every report in the window is from today.
+        assert!(rates.iter().all(|e| e.bucket == rates[0].bucket));
+        rates
+            .into_iter()
+            .map(|e| (e.key, e.errors, e.total, e.rate))
+            .collect()
+    }
+
+    #[test]
+    fn error_rates_are_weighted_and_grouped() {
+        let conn = migrated_duckdb();
+        for (days_ago, url, nel_type, phase, fraction, project_id) in [
+            (0, "https://a.com/x", "ok", "application", Some(0.5), 1),
+            (
+                0,
+                "https://a.com/y",
+                "dns.name_not_resolved",
+                "dns",
+                Some(1.0),
+                1,
+            ),
+            (
+                0,
+                "https://b.com/",
+                "tcp.timed_out",
+                "connection",
+                Some(0.25),
+                1,
+            ),
+            (0, "https://b.com/", "ok", "application", Some(1.0), 1),
+            (0, "https://c.com/", "ok", "application", None, 1),
+            (0, "https://c.com/", "http.error", "application", None, 2),
+            (30, "https://a.com/", "http.error", "application", None, 1),
+        ] {
+            conn.execute(
+                "INSERT INTO nel_report (
+                    source_ip, created_at, url, user_agent, sampling_fraction,
+                    nel_type, phase, project_id
+                ) VALUES (
+                    '', CURRENT_DATE - CAST(? AS INTEGER) + INTERVAL 1 HOUR, ?, '', ?, ?, ?, ?
+                )",
+                duckdb::params![days_ago, url, fraction, nel_type, phase, project_id],
+            )
+            .unwrap();
+        }
+
+        let key = |e: &str| Some(e.to_string());
+        assert_eq!(
+            rates(&conn, ErrorRateGroupBy::Type, Some(1)),
+            vec![
+                (key("tcp.timed_out"), 4.0, 9.0, 4.0 / 9.0),
+                (key("dns.name_not_resolved"), 1.0, 9.0, 1.0 / 9.0),
+            ]
+        );
+        assert_eq!(
+            rates(&conn, ErrorRateGroupBy::Phase, Some(1)),
+            vec![
+                (key("connection"), 4.0, 9.0, 4.0 / 9.0),
+                (key("dns"), 1.0, 9.0, 1.0 / 9.0),
+            ]
+        );
+        assert_eq!(
+            rates(&conn, ErrorRateGroupBy::Origin, Some(1)),
+            vec![
+                (key("https://b.com"), 4.0, 5.0, 0.8),
+                (key("https://a.com"), 1.0, 3.0, 1.0 / 3.0),
+            ]
+        );
+        assert_eq!(
+            rates(&conn, ErrorRateGroupBy::Origin, None),
+            vec![
+                (key("https://b.com"), 4.0, 5.0, 0.8),
+                (key("https://a.com"), 1.0, 3.0, 1.0 / 3.0),
+                (key("https://c.com"), 1.0, 2.0, 0.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn query_params_default_to_hourly_rates_by_type() {
+        let params: GetErrorRateQueryParams = serde_json::from_str("{}").unwrap();
+        assert!(matches!(params.group_by, ErrorRateGroupBy::Type));
+        assert!(matches!(params.interval, ErrorRateInterval::Hour));
+        let params: GetErrorRateQueryParams =
+            serde_json::from_str(r#"{"groupBy": "origin", "interval": "day"}"#).unwrap();
+        assert!(matches!(params.group_by, ErrorRateGroupBy::Origin));
+        assert!(matches!(params.interval, ErrorRateInterval::Day));
+    }
+
+    #[tokio::test]
+    async fn rejects_windows_past_the_date_range() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        let error_rates = |days| {
+            let query_params = GetErrorRateQueryParams {
+                group_by: ErrorRateGroupBy::Type,
+                interval: ErrorRateInterval::Day,
+                days: Some(days),
+            };
+            get_error_rates(
+                State(state.clone()),
+                extract::Query(query_params),
+                extract::Query(ProjectFilter { project_id: None }),
+            )
+        };
+        assert!(error_rates(DAYS_MAX).await.is_ok());
+        let err = error_rates(u32::MAX).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+}
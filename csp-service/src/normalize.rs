@@ -0,0 +1,154 @@
+use crate::report::CspReport;
+
+const ORIGINAL_POLICY_MAX_LEN: usize = 4096;
+const SCRIPT_SAMPLE_MAX_LEN: usize = 256;
+
+/// Keywords browsers send instead of a URL, already canonical.
+const BLOCKED_URI_KEYWORDS: [&str; 5] = [
+    "inline",
+    "eval",
+    "wasm-eval",
+    "trusted-types-policy",
+    "trusted-types-sink",
+];
+
+/// Returns the scheme of `value` if it starts with one.
+fn scheme(value: &str) -> Option<&str> {
+    let (scheme, _) = value.split_once(':')?;
+    let mut chars = scheme.chars();
+    let valid = chars.next().is_some_and(|e| e.is_ascii_alphabetic())
+        && chars.all(|e| e.is_ascii_alphanumeric() || "+-.".contains(e));
+    valid.then_some(scheme)
+}
+
+/// `scheme://host[:port]` of a hierarchical URL, without user info or default ports.
+fn origin(scheme: &str, rest: &str) -> String {
+    let authority = rest
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default()
+        .rsplit('@')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let authority = match (scheme, authority.rsplit_once(':')) {
+        ("http" | "ws", Some((host, "80"))) | ("https" | "wss", Some((host, "443"))) => {
+            host.to_owned()
+        }
+        _ => authority,
+    };
+    format!("{}://{}", scheme, authority)
+}
+
+/// Reduces a blocked URI to what identifies the violation: the origin for
+/// URLs, the scheme for `data:`, `blob:` and other opaque URIs, and a keyword
+/// for inline code and eval. Older Firefox versions report inline violations
+/// as `self`.
+pub fn canonical_blocked_uri(value: &str) -> String {
+    let value = value.trim();
+    let lower = value.to_lowercase();
+    if BLOCKED_URI_KEYWORDS.contains(&lower.as_str()) {
+        return lower;
+    }
+    if lower == "self" {
+        return "inline".to_string();
+    }
+    // Schemes are ASCII, so lowercasing one keeps the offsets into `value`
+    // intact. Lowercasing the whole value doesn't.
+    if let Some(scheme) = scheme(value) {
+        let rest = &value[scheme.len() + 1..];
+        let scheme = scheme.to_ascii_lowercase();
+        return match rest.strip_prefix("//") {
+            Some(rest) => origin(&scheme, rest),
+            None => scheme,
+        };
+    }
+    // Bare schemes such as `data` or `blob`, and anything unrecognised.
+    lower
+}
+
+fn truncate(value: &mut String, max_len: usize) {
+    if value.len() <= max_len {
+        return;
+    }
+    let mut end = max_len;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+}
+
+/// Canonicalizes a report before it is buffered so equivalent violations
+/// group together. Returns the blocked URI as sent.
+pub fn normalize_report(report: &mut CspReport) -> Option<String> {
+    truncate(&mut report.original_policy, ORIGINAL_POLICY_MAX_LEN);
+    truncate(&mut report.script_sample, SCRIPT_SAMPLE_MAX_LEN);
+    let raw = report.blocked_uri.take()?;
+    report.blocked_uri = Some(canonical_blocked_uri(&raw));
+    Some(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords() {
+        assert_eq!(canonical_blocked_uri("inline"), "inline");
+        assert_eq!(canonical_blocked_uri(" EVAL "), "eval");
+        assert_eq!(canonical_blocked_uri("self"), "inline");
+    }
+
+    #[test]
+    fn urls_reduce_to_origin() {
+        assert_eq!(
+            canonical_blocked_uri("https://CDN.example.com/a.js?x=1"),
+            "https://cdn.example.com"
+        );
+        assert_eq!(
+            canonical_blocked_uri("HTTPS://user:pw@example.com:443/"),
+            "https://example.com"
+        );
+        assert_eq!(
+            canonical_blocked_uri("http://example.com:8080#x"),
+            "http://example.com:8080"
+        );
+        assert_eq!(
+            canonical_blocked_uri("wss://example.com:443"),
+            "wss://example.com"
+        );
+    }
+
+    #[test]
+    fn opaque_uris_reduce_to_scheme() {
+        assert_eq!(canonical_blocked_uri("data:image/png;base64,AAAA"), "data");
+        assert_eq!(canonical_blocked_uri("Blob:https://example.com/x"), "blob");
+        assert_eq!(canonical_blocked_uri("data"), "data");
+    }
+
+    #[test]
+    fn non_ascii_scheme_does_not_panic() {
+        // The Kelvin sign lowercases to an ASCII `k` of a different byte length.
+        assert_eq!(canonical_blocked_uri("a\u{212A}:x"), "ak:x");
+        assert_eq!(canonical_blocked_uri("\u{212A}\u{212A}://x"), "kk://x");
+        assert_eq!(
+            canonical_blocked_uri("https://\u{212A}.example.com/"),
+            "https://k.example.com"
+        );
+    }
+
+    #[test]
+    fn truncates_on_char_boundary() {
+        let mut report = CspReport {
+            script_sample: "\u{e9}".repeat(SCRIPT_SAMPLE_MAX_LEN),
+            original_policy: "a".repeat(ORIGINAL_POLICY_MAX_LEN + 1),
+            blocked_uri: Some("https://example.com/x".to_string()),
+            ..Default::default()
+        };
+        let raw = normalize_report(&mut report);
+        assert_eq!(report.script_sample.len(), SCRIPT_SAMPLE_MAX_LEN);
+        assert_eq!(report.original_policy.len(), ORIGINAL_POLICY_MAX_LEN);
+        assert_eq!(report.blocked_uri.as_deref(), Some("https://example.com"));
+        assert_eq!(raw.as_deref(), Some("https://example.com/x"));
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::Read;
+
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, StatusCode},
+};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde_json::error::Category;
+
+use crate::report::IngestPayload;
+
+pub const MAX_BODY_BYTES_DEFAULT: usize = 64 * 1024;
+
+/// `application/csp-report` is what Firefox and Safari send for `report-uri`,
+/// `application/reports+json` is used by the Reporting API.
+const ACCEPTED_CONTENT_TYPES: [&str; 3] = [
+    "application/csp-report",
+    "application/json",
+    "application/reports+json",
+];
+
+fn check_content_type(headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|e| e.to_str().ok())
+        .map(|e| {
+            e.split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_lowercase()
+        });
+    match content_type {
+        Some(e) if ACCEPTED_CONTENT_TYPES.contains(&e.as_str()) => Ok(()),
+        Some(e) => Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!(
+                "Unsupported content type \"{}\", expected one of {}",
+                e,
+                ACCEPTED_CONTENT_TYPES.join(", ")
+            ),
+        )),
+        None => Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Missing content type".to_string(),
+        )),
+    }
+}
+
+fn read_limited<R: Read>(reader: R, max_bytes: usize) -> Result<Vec<u8>, (StatusCode, String)> {
+    let mut buf = vec![];
+    reader
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut buf)
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Error decompressing body: {}", e),
+            )
+        })?;
+    if buf.len() > max_bytes {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Decompressed body is larger than {} bytes", max_bytes),
+        ));
+    }
+    Ok(buf)
+}
+
+fn decode_body(
+    headers: &HeaderMap,
+    body: Bytes,
+    max_bytes: usize,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|e| e.to_str().ok())
+        .map(|e| e.trim().to_lowercase())
+        .unwrap_or_default();
+    match encoding.as_str() {
+        "" | "identity" => Ok(body.to_vec()),
+        "gzip" | "x-gzip" => read_limited(GzDecoder::new(&body[..]), max_bytes),
+        "deflate" => read_limited(ZlibDecoder::new(&body[..]), max_bytes),
+        "br" => read_limited(
+            brotli_decompressor::Decompressor::new(&body[..], 4096),
+            max_bytes,
+        ),
+        e => Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Unsupported content encoding \"{}\"", e),
+        )),
+    }
+}
+
+/// Validates and decodes a report request body. The compressed size is
+/// capped by the route's body limit, the decompressed size by `max_bytes`.
+pub fn parse_payload(
+    headers: &HeaderMap,
+    body: Bytes,
+    max_bytes: usize,
+) -> Result<IngestPayload, (StatusCode, String)> {
+    check_content_type(headers)?;
+    let body = decode_body(headers, body, max_bytes)?;
+    // Pick the format up front, an untagged enum would hide the real error.
+    let is_batch = body.iter().find(|e| !e.is_ascii_whitespace()) == Some(&b'[');
+    let res = if is_batch {
+        serde_json::from_slice(&body).map(IngestPayload::ReportingApi)
+    } else {
+        serde_json::from_slice(&body).map(IngestPayload::Legacy)
+    };
+    res.map_err(|e| match e.classify() {
+        Category::Data => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Invalid report: {}", e),
+        ),
+        _ => (StatusCode::BAD_REQUEST, format!("Malformed JSON: {}", e)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const LEGACY: &str =
+        r#"{"csp-report": {"document-uri": "https://example.com/", "blocked-uri": "inline"}}"#;
+
+    fn gzip(body: &[u8]) -> Bytes {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap().into()
+    }
+
+    #[test]
+    fn parses_legacy_reports() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/csp-report; charset=utf-8".parse().unwrap(),
+        );
+        match parse_payload(&headers, LEGACY.into(), 1024).unwrap() {
+            IngestPayload::Legacy(e) => {
+                assert_eq!(e.csp_report.document_uri, "https://example.com/")
+            }
+            e => panic!("expected a legacy report, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parses_compressed_batches() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/reports+json".parse().unwrap(),
+        );
+        headers.insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+        let body = gzip(br#" [{"type": "csp-violation", "age": 10, "body": {}}]"#);
+        match parse_payload(&headers, body, 1024).unwrap() {
+            IngestPayload::ReportingApi(e) => {
+                assert_eq!(e.len(), 1);
+                assert_eq!(e[0].report_type, "csp-violation");
+            }
+            e => panic!("expected a batch, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_requests() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        let err = parse_payload(&headers, LEGACY.into(), 1024).unwrap_err();
+        assert_eq!(err.0, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        assert_eq!(
+            parse_payload(&headers, "{".into(), 1024).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            parse_payload(&headers, "[1]".into(), 1024).unwrap_err().0,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        headers.insert(header::CONTENT_ENCODING, "zstd".parse().unwrap());
+        let err = parse_payload(&headers, LEGACY.into(), 1024).unwrap_err();
+        assert_eq!(err.0, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn caps_the_decompressed_size() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        headers.insert(header::CONTENT_ENCODING, "gzip".parse().unwrap());
+        let body = gzip(format!("[{}]", " ".repeat(4096)).as_bytes());
+        let err = parse_payload(&headers, body, 1024).unwrap_err();
+        assert_eq!(err.0, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
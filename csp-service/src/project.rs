@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use base64::{engine::general_purpose, Engine as _};
+use deadpool_sqlite::{
+    rusqlite::{params, Connection},
+    Pool as SQLitePool,
+};
+use duckdb::ToSql;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{state::AppState, utils::internal_error};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Project {
+    id: i64,
+    name: String,
+    key: String,
+    created_at: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewProject {
+    pub name: String,
+}
+
+/// Scopes a query to a single project, every project when unset.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProjectFilter {
+    pub project_id: Option<i64>,
+}
+
+impl ProjectFilter {
+    /// Returns the SQL condition for the filter, binding its parameter.
+    pub fn condition<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) -> &'static str {
+        match &self.project_id {
+            Some(project_id) => {
+                params.push(project_id);
+                "project_id = ?"
+            }
+            None => "TRUE",
+        }
+    }
+}
+
+/// Project ids by ingest key, so the report route doesn't hit SQLite.
+#[derive(Clone, Default)]
+pub struct ProjectKeys(Arc<RwLock<HashMap<String, i64>>>);
+
+impl ProjectKeys {
+    pub fn get(&self, key: &str) -> Option<i64> {
+        self.0.read().unwrap().get(key).copied()
+    }
+
+    pub async fn reload(&self, db_pool: &SQLitePool) -> Result<(), (StatusCode, String)> {
+        let db_conn = db_pool.get().await.map_err(internal_error)?;
+        let keys = db_conn
+            .interact(|conn| {
+                let mut stmt = conn.prepare("SELECT key, id FROM project")?;
+                let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect::<Result<HashMap<String, i64>, _>>()
+            })
+            .await
+            .map_err(internal_error)?
+            .map_err(internal_error)?;
+        *self.0.write().unwrap() = keys;
+        Ok(())
+    }
+}
+
+fn list_projects(conn: &mut Connection) -> Result<Vec<Project>, (StatusCode, String)> {
+    let mut stmt = conn
+        .prepare("SELECT id, name, key, created_at FROM project")
+        .map_err(internal_error)?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(Project {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                key: row.get(2)?,
+                created_at: row.get(3)?,
+            })
+        })
+        .map_err(internal_error)?;
+    rows.collect::<Result<Vec<Project>, _>>()
+        .map_err(internal_error)
+}
+
+pub async fn get_projects(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Project>>, (StatusCode, String)> {
+    let db_conn = state.db_pool.get().await.map_err(internal_error)?;
+    let projects = db_conn
+        .interact(list_projects)
+        .await
+        .map_err(internal_error)??;
+    Ok(Json(projects))
+}
+
+pub async fn create_project(
+    State(state): State<AppState>,
+    Json(new_project): Json<NewProject>,
+) -> Result<Json<Project>, (StatusCode, String)> {
+    let name = new_project.name.trim().to_owned();
+    if name.is_empty() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Project name can't be empty".to_string(),
+        ));
+    }
+    let random_bytes = rand::thread_rng().gen::<[u8; 18]>();
+    let key = general_purpose::URL_SAFE_NO_PAD.encode(random_bytes);
+    let created_at = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+
+    let db_conn = state.db_pool.get().await.map_err(internal_error)?;
+    let project = db_conn
+        .interact(move |conn| {
+            conn.execute(
+                "INSERT INTO project (name, key, created_at) VALUES (?, ?, ?)",
+                params![name, key, created_at],
+            )
+            .map_err(internal_error)?;
+            Ok(Project {
+                id: conn.last_insert_rowid(),
+                name,
+                key,
+                created_at,
+            })
+        })
+        .await
+        .map_err(internal_error)??;
+    state.project_keys.reload(&state.db_pool).await?;
+    Ok(Json(project))
+}
+
+pub async fn delete_project(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<Project>>, (StatusCode, String)> {
+    let db_conn = state.db_pool.get().await.map_err(internal_error)?;
+    let projects = db_conn
+        .interact(move |conn| {
+            conn.execute("DELETE FROM project WHERE id = ?", params![id])
+                .map_err(internal_error)?;
+            list_projects(conn)
+        })
+        .await
+        .map_err(internal_error)??;
+    state.project_keys.reload(&state.db_pool).await?;
+    Ok(Json(projects))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        extract::Query,
+        http::{header, HeaderMap},
+    };
+
+    use super::*;
+    use crate::{
+        client_ip::ClientIp,
+        ingest::flush,
+        report::{
+            get_distinct_reports, get_reports, get_violation_count_by_day, report_csp,
+            report_project_csp, GetDistinctReportQueryParams, GetReportQueryParams,
+        },
+        test_utils::{app_state, TempDir},
+    };
+
+    async fn create(state: &AppState, name: &str) -> Project {
+        let new_project = NewProject {
+            name: name.to_string(),
+        };
+        create_project(State(state.clone()), Json(new_project))
+            .await
+            .unwrap()
+            .0
+    }
+
+    fn report(document_uri: &str) -> (HeaderMap, axum::body::Bytes) {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/csp-report".parse().unwrap(),
+        );
+        let body = format!(
+            r#"{{"csp-report": {{
+                "document-uri": "{}",
+                "violated-directive": "script-src",
+                "effective-directive": "script-src",
+                "blocked-uri": "inline"
+            }}}}"#,
+            document_uri
+        );
+        (headers, body.into())
+    }
+
+    async fn report_to(state: &AppState, key: &str, document_uri: &str) -> StatusCode {
+        let (headers, body) = report(document_uri);
+        match report_project_csp(
+            State(state.clone()),
+            Path(key.to_string()),
+            ClientIp(None),
+            headers,
+            body,
+        )
+        .await
+        {
+            Ok(_) => StatusCode::OK,
+            Err((status, _)) => status,
+        }
+    }
+
+    fn filter(project_id: Option<i64>) -> Query<ProjectFilter> {
+        Query(ProjectFilter { project_id })
+    }
+
+    #[tokio::test]
+    async fn creates_projects_with_random_keys() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        let a = create(&state, " Shop ").await;
+        let b = create(&state, "Blog").await;
+        assert_eq!(a.name, "Shop");
+        assert_ne!(a.key, b.key);
+        assert_eq!(a.key.len(), 24);
+        assert!(a
+            .key
+            .chars()
+            .all(|e| e.is_ascii_alphanumeric() || e == '-' || e == '_'));
+
+        let err = create_project(
+            State(state.clone()),
+            Json(NewProject {
+                name: "  ".to_string(),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let projects = get_projects(State(state.clone())).await.unwrap().0;
+        let ids: Vec<i64> = projects.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![a.id, b.id]);
+    }
+
+    #[tokio::test]
+    async fn project_keys_follow_created_and_deleted_projects() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        let project = create(&state, "Shop").await;
+        assert_eq!(state.project_keys.get(&project.key), Some(project.id));
+        assert_eq!(
+            report_to(&state, &project.key, "https://a.com/").await,
+            StatusCode::OK
+        );
+
+        let projects = delete_project(State(state.clone()), Path(project.id as u64))
+            .await
+            .unwrap()
+            .0;
+        assert!(projects.is_empty());
+        assert_eq!(state.project_keys.get(&project.key), None);
+        assert_eq!(
+            report_to(&state, &project.key, "https://a.com/").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_are_scoped_to_their_project() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        let a = create(&state, "A").await;
+        let b = create(&state, "B").await;
+        assert_eq!(
+            report_to(&state, &a.key, "https://a.com/1").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            report_to(&state, &a.key, "https://a.com/2").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            report_to(&state, &b.key, "https://b.com/").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            report_to(&state, "unknown", "https://c.com/").await,
+            StatusCode::NOT_FOUND
+        );
+        let (headers, body) = report("https://default.com/");
+        report_csp(State(state.clone()), ClientIp(None), headers, body)
+            .await
+            .unwrap();
+        flush(&state, false).await;
+
+        for (project_id, expected) in [
+            (Some(a.id), vec!["https://a.com/1", "https://a.com/2"]),
+            (Some(b.id), vec!["https://b.com/"]),
+            (
+                None,
+                vec![
+                    "https://a.com/1",
+                    "https://a.com/2",
+                    "https://b.com/",
+                    "https://default.com/",
+                ],
+            ),
+        ] {
+            let query = Query(GetReportQueryParams {
+                limit: None,
+                offset: None,
+            });
+            let reports = get_reports(State(state.clone()), query, filter(project_id))
+                .await
+                .unwrap()
+                .0;
+            let mut uris: Vec<&str> = reports.iter().map(|e| e.document_uri.as_str()).collect();
+            uris.sort();
+            assert_eq!(uris, expected);
+            assert!(reports
+                .iter()
+                .all(|e| project_id.is_none() || e.project_id == project_id));
+
+            let query = Query(GetDistinctReportQueryParams {
+                limit: None,
+                offset: None,
+            });
+            let distinct = get_distinct_reports(State(state.clone()), query, filter(project_id))
+                .await
+                .unwrap()
+                .0;
+            let total: u64 = distinct.iter().map(|e| e.cnt).sum();
+            assert_eq!(total, expected.len() as u64);
+
+            let counts = get_violation_count_by_day(State(state.clone()), filter(project_id))
+                .await
+                .unwrap()
+                .0;
+            let script_src: u64 = counts.iter().map(|e| e.script_src).sum();
+            assert_eq!(script_src, expected.len() as u64);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    env,
+    net::IpAddr,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{client_ip::ClientIp, state::AppState};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// Most clients with a bucket at once. New clients are limited while every
+/// tracked client is still refilling.
+const MAX_CLIENTS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl Limit {
+    /// Reads a limit from `{prefix}` and `{prefix}_BURST`. A rate of 0 disables the limit.
+    fn from_env(prefix: &str) -> Option<Self> {
+        let per_second: f64 = env::var(prefix)
+            .unwrap_or("0".to_string())
+            .parse()
+            .unwrap_or(0.0);
+        if per_second <= 0.0 {
+            return None;
+        }
+        let burst: f64 = env::var(format!("{}_BURST", prefix))
+            .ok()
+            .and_then(|e| e.parse().ok())
+            .unwrap_or(per_second);
+        Some(Limit {
+            per_second,
+            burst: burst.max(1.0),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &Limit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.burst,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.updated_at = now;
+    }
+
+    fn try_take(&mut self, limit: &Limit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The address a client is limited by. IPv6 clients usually get a whole /64,
+/// so they share a bucket per /64 rather than one per address.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6((u128::from(v6) & !u128::from(u64::MAX)).into()),
+        },
+    }
+}
+
+/// Drops the buckets that have refilled, their clients are indistinguishable
+/// from clients we have never seen.
+fn prune_buckets(buckets: &mut HashMap<IpAddr, TokenBucket>, limit: &Limit, now: Instant) {
+    buckets.retain(|_, bucket| {
+        bucket.refill(limit, now);
+        bucket.tokens < limit.burst
+    });
+}
+
+/// Token bucket rate limiting for the public report endpoint, both per client
+/// IP and across all clients.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_ip_limit: Option<Limit>,
+    global_limit: Option<Limit>,
+    per_ip: Mutex<HashMap<IpAddr, TokenBucket>>,
+    global: Mutex<Option<TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn from_env() -> Self {
+        RateLimiter {
+            per_ip_limit: Limit::from_env("METLO_RATE_LIMIT_PER_IP"),
+            global_limit: Limit::from_env("METLO_RATE_LIMIT_GLOBAL"),
+            per_ip: Mutex::new(HashMap::new()),
+            global: Mutex::new(None),
+        }
+    }
+
+    pub fn check(&self, client_ip: Option<IpAddr>) -> bool {
+        let now = Instant::now();
+        if let (Some(limit), Some(ip)) = (&self.per_ip_limit, client_ip) {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            let key = client_key(ip);
+            if !per_ip.contains_key(&key) && per_ip.len() >= MAX_CLIENTS {
+                prune_buckets(&mut per_ip, limit, now);
+                if per_ip.len() >= MAX_CLIENTS {
+                    return false;
+                }
+            }
+            let bucket = per_ip
+                .entry(key)
+                .or_insert_with(|| TokenBucket::new(limit, now));
+            if !bucket.try_take(limit, now) {
+                return false;
+            }
+        }
+        if let Some(limit) = &self.global_limit {
+            let mut global = self.global.lock().unwrap();
+            let bucket = global.get_or_insert_with(|| TokenBucket::new(limit, now));
+            if !bucket.try_take(limit, now) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn prune(&self) {
+        if let Some(limit) = &self.per_ip_limit {
+            prune_buckets(&mut self.per_ip.lock().unwrap(), limit, Instant::now());
+        }
+    }
+}
+
+pub async fn run_pruner(state: AppState) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.rate_limiter.prune();
+    }
+}
+
+pub async fn rate_limit_middleware<B>(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !state.rate_limiter.check(client_ip) {
+        state
+            .metrics
+            .requests_rate_limited
+            .fetch_add(1, Ordering::Relaxed);
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, "1")],
+            "Too many requests",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_second: f64, burst: f64) -> RateLimiter {
+        RateLimiter {
+            per_ip_limit: Some(Limit { per_second, burst }),
+            global_limit: None,
+            per_ip: Mutex::new(HashMap::new()),
+            global: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn ipv6_clients_share_a_bucket_per_64() {
+        assert_eq!(
+            client_key("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            client_key("::ffff:192.0.2.1".parse().unwrap()),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+
+        let limiter = limiter(0.001, 1.0);
+        assert!(limiter.check(Some("2001:db8::1".parse().unwrap())));
+        assert!(!limiter.check(Some("2001:db8::2".parse().unwrap())));
+        assert!(limiter.check(Some("2001:db8:0:1::1".parse().unwrap())));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limit = Limit {
+            per_second: 2.0,
+            burst: 2.0,
+        };
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&limit, now);
+        assert!(bucket.try_take(&limit, now));
+        assert!(bucket.try_take(&limit, now));
+        assert!(!bucket.try_take(&limit, now));
+        assert!(bucket.try_take(&limit, now + Duration::from_millis(500)));
+        bucket.refill(&limit, now + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[test]
+    fn new_clients_are_limited_once_full() {
+        let limiter = limiter(0.001, 1.0);
+        let limit = limiter.per_ip_limit.unwrap();
+        {
+            let now = Instant::now();
+            let mut per_ip = limiter.per_ip.lock().unwrap();
+            for i in 0..MAX_CLIENTS as u32 {
+                let mut bucket = TokenBucket::new(&limit, now);
+                bucket.tokens = 0.0;
+                per_ip.insert(IpAddr::V4(i.into()), bucket);
+            }
+        }
+        assert!(!limiter.check(Some("2001:db8::1".parse().unwrap())));
+        assert_eq!(limiter.per_ip.lock().unwrap().len(), MAX_CLIENTS);
+    }
+}
@@ -0,0 +1,240 @@
+use std::{env, fs};
+
+use regex::Regex;
+
+use crate::{
+    report::BufferItem,
+    report_types::{find_report_type, TypedReport},
+};
+
+const REDACTED: &str = "REDACTED";
+
+/// Removes personal data from the URLs of a report before it is queued.
+///
+/// `METLO_REDACT_STRIP_QUERY` drops the query string and fragment entirely,
+/// `METLO_REDACT_QUERY_PARAMS` replaces the values of the listed query
+/// parameters and `METLO_REDACT_PATTERNS_PATH` points to a file with one regex
+/// per line whose matches are replaced.
+#[derive(Debug, Default)]
+pub struct Redactor {
+    strip_query: bool,
+    query_params: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let strip_query = env::var("METLO_REDACT_STRIP_QUERY")
+            .map(|e| e == "true")
+            .unwrap_or(false);
+        let query_params = env::var("METLO_REDACT_QUERY_PARAMS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|e| e.trim().to_lowercase())
+            .filter(|e| !e.is_empty())
+            .collect();
+        let mut patterns = vec![];
+        if let Ok(path) = env::var("METLO_REDACT_PATTERNS_PATH") {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Error reading redaction patterns from {}: {}", path, e))?;
+            for line in contents.lines().map(str::trim).filter(|e| !e.is_empty()) {
+                patterns.push(
+                    Regex::new(line)
+                        .map_err(|e| format!("Invalid redaction pattern {}: {}", line, e))?,
+                );
+            }
+        }
+        Ok(Redactor {
+            strip_query,
+            query_params,
+            patterns,
+        })
+    }
+
+    fn redact_query(&self, query: &str) -> String {
+        query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) if self.query_params.contains(&name.to_lowercase()) => {
+                    format!("{}={}", name, REDACTED)
+                }
+                _ => pair.to_owned(),
+            })
+            .collect::<Vec<String>>()
+            .join("&")
+    }
+
+    fn redact_url(&self, url: &str) -> String {
+        let mut res = if self.strip_query {
+            url.split(['?', '#']).next().unwrap_or_default().to_owned()
+        } else if self.query_params.is_empty() {
+            url.to_owned()
+        } else {
+            match url.split_once('?') {
+                Some((base, rest)) => {
+                    let (query, fragment) = match rest.split_once('#') {
+                        Some((query, fragment)) => (query, Some(fragment)),
+                        None => (rest, None),
+                    };
+                    let mut res = format!("{}?{}", base, self.redact_query(query));
+                    if let Some(fragment) = fragment {
+                        res.push('#');
+                        res.push_str(fragment);
+                    }
+                    res
+                }
+                None => url.to_owned(),
+            }
+        };
+        for pattern in self.patterns.iter() {
+            res = pattern.replace_all(&res, REDACTED).into_owned();
+        }
+        res
+    }
+
+    /// Redacts `document_uri`, `referrer` and the URLs kept as reported,
+    /// marking the item if any of them changed.
+    pub fn redact(&self, item: &mut BufferItem) {
+        let urls = [&mut item.document_uri, &mut item.referrer]
+            .into_iter()
+            .chain(item.blocked_uri_raw.as_mut())
+            .chain(item.source_file.as_mut());
+        for url in urls {
+            let redacted = self.redact_url(url);
+            if redacted != *url {
+                *url = redacted;
+                item.redacted = true;
+            }
+        }
+    }
+
+    /// Redacts the `url` of a typed report and the URL fields of its body.
+    pub fn redact_typed(&self, report: &mut TypedReport) {
+        report.url = self.redact_url(&report.url);
+        let url_fields = find_report_type(&report.report_type).map_or(&[][..], |e| e.url_fields);
+        for field in url_fields {
+            if let Some(serde_json::Value::String(url)) = report.body.get_mut(*field) {
+                *url = self.redact_url(url);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(strip_query: bool, query_params: &[&str], patterns: &[&str]) -> Redactor {
+        Redactor {
+            strip_query,
+            query_params: query_params.iter().map(|e| e.to_string()).collect(),
+            patterns: patterns.iter().map(|e| Regex::new(e).unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn strips_query_and_fragment() {
+        let redactor = redactor(true, &[], &[]);
+        assert_eq!(
+            redactor.redact_url("https://example.com/a?email=x#y"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            redactor.redact_url("https://example.com/a#y"),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn redacts_listed_query_params() {
+        let redactor = redactor(false, &["email", "token"], &[]);
+        assert_eq!(
+            redactor.redact_url("https://example.com/?Email=a@b.com&page=2&token=t#frag"),
+            "https://example.com/?Email=REDACTED&page=2&token=REDACTED#frag"
+        );
+        assert_eq!(
+            redactor.redact_url("https://example.com/email=x"),
+            "https://example.com/email=x"
+        );
+    }
+
+    #[test]
+    fn replaces_pattern_matches() {
+        let redactor = redactor(false, &[], &[r"/users/\d+"]);
+        assert_eq!(
+            redactor.redact_url("https://example.com/users/42/profile"),
+            "https://example.comREDACTED/profile"
+        );
+    }
+
+    #[test]
+    fn marks_redacted_items() {
+        let redactor = redactor(true, &[], &[]);
+        let mut item = BufferItem {
+            document_uri: "https://example.com/".to_string(),
+            referrer: "https://example.com/?q=1".to_string(),
+            ..Default::default()
+        };
+        redactor.redact(&mut item);
+        assert_eq!(item.referrer, "https://example.com/");
+        assert!(item.redacted);
+
+        let mut item = BufferItem {
+            document_uri: "https://example.com/".to_string(),
+            ..Default::default()
+        };
+        redactor.redact(&mut item);
+        assert!(!item.redacted);
+    }
+
+    #[test]
+    fn redacts_raw_blocked_uri_and_source_file() {
+        let redactor = redactor(false, &["email"], &[]);
+        let mut item = BufferItem {
+            blocked_uri: Some("https://cdn.example.com".to_string()),
+            blocked_uri_raw: Some("https://cdn.example.com/a.js?email=a@b.com".to_string()),
+            source_file: Some("https://example.com/?email=a@b.com&page=2".to_string()),
+            ..Default::default()
+        };
+        redactor.redact(&mut item);
+        assert_eq!(
+            item.blocked_uri_raw.as_deref(),
+            Some("https://cdn.example.com/a.js?email=REDACTED")
+        );
+        assert_eq!(
+            item.source_file.as_deref(),
+            Some("https://example.com/?email=REDACTED&page=2")
+        );
+        assert!(item.redacted);
+    }
+
+    #[test]
+    fn redacts_typed_report_urls() {
+        let redactor = redactor(true, &[], &[]);
+        let mut report = TypedReport {
+            report_type: "network-error".to_string(),
+            url: "https://example.com/reset?code=123".to_string(),
+            body: serde_json::json!({
+                "referrer": "https://example.com/?email=a@b.com",
+                "phase": "dns?x",
+            }),
+            ..Default::default()
+        };
+        redactor.redact_typed(&mut report);
+        assert_eq!(report.url, "https://example.com/reset");
+        assert_eq!(report.body["referrer"], "https://example.com/");
+        assert_eq!(report.body["phase"], "dns?x");
+
+        let mut report = TypedReport {
+            report_type: "coop".to_string(),
+            body: serde_json::json!({
+                "nextResponseURL": "https://example.com/b?t=1",
+                "referrer": null,
+            }),
+            ..Default::default()
+        };
+        redactor.redact_typed(&mut report);
+        assert_eq!(report.body["nextResponseURL"], "https://example.com/b");
+        assert!(report.body["referrer"].is_null());
+    }
+}
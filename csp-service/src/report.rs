@@ -1,13 +1,26 @@
 use axum::{
-    extract::{self, State},
-    http::StatusCode,
+    body::Bytes,
+    extract::{self, Path, State},
+    http::{header, HeaderMap, StatusCode},
     Json,
 };
 use duckdb::{params_from_iter, ToSql};
-use log::error;
+use log::warn;
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 
-use crate::{state::AppState, utils::internal_error, REPORT_BUFFER};
+use crate::{
+    client_ip::ClientIp,
+    ingest::IngestItem,
+    normalize::normalize_report,
+    payload::parse_payload,
+    project::ProjectFilter,
+    report_types::{find_report_type, TypedReport},
+    rollup,
+    state::AppState,
+    user_agent::parse_user_agent,
+    utils::internal_error,
+};
 
 #[derive(Debug, Default, Deserialize)]
 #[serde(rename_all = "kebab-case", default)]
@@ -43,11 +56,32 @@ pub struct BufferItem {
     pub status_code: Option<u32>,
     pub script_sample: String,
     pub source_ip: String,
+    pub user_agent: String,
+    pub browser_family: String,
+    pub browser_version: String,
+    pub os: String,
+    /// How many reports this row stands for, see `Sampler`.
+    #[serde(default = "default_sample_weight")]
+    pub sample_weight: u32,
+    #[serde(default)]
+    pub project_id: Option<i64>,
+    /// `blocked_uri` as sent, before normalization.
+    #[serde(default)]
+    pub blocked_uri_raw: Option<String>,
+    /// Whether personal data was removed from `document_uri` or `referrer`.
+    #[serde(default)]
+    pub redacted: bool,
+}
+
+fn default_sample_weight() -> u32 {
+    1
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DistinctReport {
+    /// Stable identifier of the distinct report, see `rollup::fingerprint`.
+    pub fingerprint: String,
     pub violated_directive: String,
     pub effective_directive: String,
     pub original_policy: String,
@@ -56,6 +90,7 @@ pub struct DistinctReport {
     pub source_file: Option<String>,
     pub script_sample: String,
     pub first_seen: String,
+    pub last_seen: String,
     pub cnt: u64,
 }
 
@@ -80,6 +115,74 @@ pub struct ReportPayload {
     pub csp_report: CspReport,
 }
 
+/// A single entry of a Reporting API (`report-to` / `Reporting-Endpoints`) batch.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ReportingApiReport {
+    #[serde(rename = "type")]
+    pub report_type: String,
+    pub age: i64,
+    pub url: String,
+    pub user_agent: String,
+    pub body: serde_json::Value,
+}
+
+/// The body of a Reporting API report with type `csp-violation`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CspViolationBody {
+    #[serde(rename = "documentURL")]
+    pub document_url: String,
+    pub referrer: Option<String>,
+    #[serde(rename = "blockedURL")]
+    pub blocked_url: Option<String>,
+    pub effective_directive: String,
+    pub original_policy: String,
+    pub source_file: Option<String>,
+    pub sample: Option<String>,
+    pub disposition: String,
+    pub status_code: Option<u32>,
+    pub line_number: Option<u32>,
+    pub column_number: Option<u32>,
+}
+
+impl From<CspViolationBody> for CspReport {
+    fn from(body: CspViolationBody) -> Self {
+        CspReport {
+            document_uri: body.document_url,
+            referrer: body.referrer.unwrap_or_default(),
+            // The Reporting API dropped `violatedDirective` in favour of `effectiveDirective`.
+            violated_directive: body.effective_directive.clone(),
+            effective_directive: body.effective_directive,
+            original_policy: body.original_policy,
+            disposition: body.disposition,
+            blocked_uri: body.blocked_url,
+            line_number: body.line_number,
+            column_number: body.column_number,
+            source_file: body.source_file,
+            status_code: body.status_code,
+            script_sample: body.sample.unwrap_or_default(),
+        }
+    }
+}
+
+/// Browsers either send a single legacy `report-uri` object or a batch of
+/// Reporting API reports.
+#[derive(Debug)]
+pub enum IngestPayload {
+    ReportingApi(Vec<ReportingApiReport>),
+    Legacy(Box<ReportPayload>),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrowserCount {
+    pub browser_family: String,
+    pub browser_version: String,
+    pub os: String,
+    pub cnt: u64,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetReportQueryParams {
@@ -94,59 +197,185 @@ pub struct GetDistinctReportQueryParams {
     pub offset: Option<u32>,
 }
 
+fn make_buffer_item(
+    mut report: CspReport,
+    created_at: chrono::DateTime<chrono::Utc>,
+    source_ip: String,
+    user_agent: String,
+    project_id: Option<i64>,
+) -> BufferItem {
+    let parsed_user_agent = parse_user_agent(&user_agent);
+    let blocked_uri_raw = normalize_report(&mut report);
+    BufferItem {
+        document_uri: report.document_uri,
+        created_at: created_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        referrer: report.referrer,
+        violated_directive: report.violated_directive,
+        effective_directive: report.effective_directive,
+        original_policy: report.original_policy,
+        disposition: report.disposition,
+        blocked_uri: report.blocked_uri,
+        line_number: report.line_number,
+        column_number: report.column_number,
+        source_file: report.source_file,
+        status_code: report.status_code,
+        script_sample: report.script_sample,
+        source_ip,
+        user_agent,
+        browser_family: parsed_user_agent.browser_family,
+        browser_version: parsed_user_agent.browser_version,
+        os: parsed_user_agent.os,
+        sample_weight: 1,
+        project_id,
+        blocked_uri_raw,
+        redacted: false,
+    }
+}
+
 pub async fn report_csp(
-    extract::Json(payload): extract::Json<ReportPayload>,
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Result<&'static str, (StatusCode, String)> {
-    let source_ip = "".to_owned();
-    let report = payload.csp_report;
-    if let Ok(ref mut buf_write) = REPORT_BUFFER.try_write() {
-        buf_write.push(BufferItem {
-            document_uri: report.document_uri,
-            created_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
-            referrer: report.referrer,
-            violated_directive: report.violated_directive,
-            effective_directive: report.effective_directive,
-            original_policy: report.original_policy,
-            disposition: report.disposition,
-            blocked_uri: report.blocked_uri,
-            line_number: report.line_number,
-            column_number: report.column_number,
-            source_file: report.source_file,
-            status_code: report.status_code,
-            script_sample: report.script_sample,
-            source_ip,
-        });
-    }
+    ingest_reports(state, None, client_ip, headers, body).await
+}
+
+pub async fn report_project_csp(
+    State(state): State<AppState>,
+    Path(project_key): Path<String>,
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<&'static str, (StatusCode, String)> {
+    let project_id = state
+        .project_keys
+        .get(&project_key)
+        .ok_or((StatusCode::NOT_FOUND, "Unknown project".to_string()))?;
+    ingest_reports(state, Some(project_id), client_ip, headers, body).await
+}
+
+async fn ingest_reports(
+    state: AppState,
+    project_id: Option<i64>,
+    client_ip: Option<IpAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<&'static str, (StatusCode, String)> {
+    let payload = parse_payload(&headers, body, state.max_body_bytes)?;
+    let source_ip = client_ip.map(|e| e.to_string()).unwrap_or_default();
+    let header_user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|e| e.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let now = chrono::Utc::now();
+    let items: Vec<IngestItem> = match payload {
+        IngestPayload::Legacy(payload) => {
+            vec![IngestItem::Csp(Box::new(make_buffer_item(
+                payload.csp_report,
+                now,
+                source_ip,
+                header_user_agent,
+                project_id,
+            )))]
+        }
+        IngestPayload::ReportingApi(reports) => reports
+            .into_iter()
+            .filter_map(|report| {
+                // A bogus age far enough in the past would overflow the timestamp.
+                let created_at = now
+                    .checked_sub_signed(chrono::Duration::milliseconds(report.age.max(0)))
+                    .unwrap_or(now);
+                let user_agent = if report.user_agent.is_empty() {
+                    header_user_agent.clone()
+                } else {
+                    report.user_agent
+                };
+                if report.report_type != "csp-violation" {
+                    if find_report_type(&report.report_type).is_none() {
+                        warn!("Skipping unsupported report type: {}", report.report_type);
+                        return None;
+                    }
+                    return Some(IngestItem::Typed(TypedReport {
+                        report_type: report.report_type,
+                        created_at: created_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                        url: report.url,
+                        user_agent,
+                        source_ip: source_ip.clone(),
+                        body: report.body,
+                        project_id,
+                    }));
+                }
+                let body: CspViolationBody = match serde_json::from_value(report.body) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        warn!("Skipping malformed csp-violation report: {}", e);
+                        return None;
+                    }
+                };
+                Some(IngestItem::Csp(Box::new(make_buffer_item(
+                    body.into(),
+                    created_at,
+                    source_ip.clone(),
+                    user_agent,
+                    project_id,
+                ))))
+            })
+            .collect(),
+    };
+    let items: Vec<IngestItem> = items
+        .into_iter()
+        .filter_map(|mut item| {
+            match &mut item {
+                IngestItem::Csp(report) => {
+                    if !state.noise_filter.keep(report) {
+                        return None;
+                    }
+                    state.redactor.redact(report);
+                }
+                IngestItem::Typed(report) => state.redactor.redact_typed(report),
+            }
+            Some(item)
+        })
+        .collect();
+    state.ingest.push(items).await?;
     Ok("OK")
 }
 
 pub async fn get_violation_count_by_day(
     State(state): State<AppState>,
+    extract::Query(project): extract::Query<ProjectFilter>,
 ) -> Result<Json<Vec<ViolationCount>>, (StatusCode, String)> {
     let conn = state.duckdb_pool.get().map_err(internal_error)?;
 
-    let query = "
+    let mut params: Vec<&dyn ToSql> = vec![];
+    let query = format!(
+        "
         SELECT
             CAST(CAST(created_at AS DATE) AS TEXT) AS day,
-            COUNT(CASE WHEN violated_directive ILIKE 'base-uri%' THEN 1 END) AS base_uri,
-            COUNT(CASE WHEN violated_directive ILIKE 'script-src%' THEN 1 END) AS script_src,
-            COUNT(CASE WHEN violated_directive ILIKE 'img-src%' THEN 1 END) AS img_src,
-            COUNT(CASE WHEN violated_directive ILIKE 'style-src%' THEN 1 END) AS style_src,
-            COUNT(CASE WHEN violated_directive ILIKE 'connect-src%' THEN 1 END) AS connect_src,
-            COUNT(CASE WHEN violated_directive ILIKE 'media-src%' THEN 1 END) AS media_src,
-            COUNT(CASE WHEN violated_directive ILIKE 'object-src%' THEN 1 END) AS object_src,
-            COUNT(CASE WHEN violated_directive ILIKE 'frame-src%' THEN 1 END) AS frame_src,
-            COUNT(CASE WHEN violated_directive ILIKE 'font-src%' THEN 1 END) AS font_src,
+            CAST(SUM(CASE WHEN violated_directive ILIKE 'base-uri%' THEN sample_weight ELSE 0 END) AS UBIGINT) AS base_uri,
+            CAST(SUM(CASE WHEN violated_directive ILIKE 'script-src%' THEN sample_weight ELSE 0 END) AS UBIGINT) AS script_src,
+            CAST(SUM(CASE WHEN violated_directive ILIKE 'img-src%' THEN sample_weight ELSE 0 END) AS UBIGINT) AS img_src,
+            CAST(SUM(CASE WHEN violated_directive ILIKE 'style-src%' THEN sample_weight ELSE 0 END) AS UBIGINT) AS style_src,
+            CAST(SUM(CASE WHEN violated_directive ILIKE 'connect-src%' THEN sample_weight ELSE 0 END) AS UBIGINT) AS connect_src,
+            CAST(SUM(CASE WHEN violated_directive ILIKE 'media-src%' THEN sample_weight ELSE 0 END) AS UBIGINT) AS media_src,
+            CAST(SUM(CASE WHEN violated_directive ILIKE 'object-src%' THEN sample_weight ELSE 0 END) AS UBIGINT) AS object_src,
+            CAST(SUM(CASE WHEN violated_directive ILIKE 'frame-src%' THEN sample_weight ELSE 0 END) AS UBIGINT) AS frame_src,
+            CAST(SUM(CASE WHEN violated_directive ILIKE 'font-src%' THEN sample_weight ELSE 0 END) AS UBIGINT) AS font_src,
         FROM csp_report
+        WHERE {}
         GROUP BY 1
         ORDER BY 1 ASC
         LIMIT 14
-    ";
+    ",
+        project.condition(&mut params)
+    );
 
-    let mut stmt = conn.prepare(query).map_err(internal_error)?;
+    let mut stmt = conn.prepare(&query).map_err(internal_error)?;
 
     let res: Vec<ViolationCount> = stmt
-        .query_map([], |e| {
+        .query_map(params_from_iter(params), |e| {
             Ok(ViolationCount {
                 day: e.get(0)?,
                 base_uri: e.get(1)?,
@@ -167,13 +396,56 @@ pub async fn get_violation_count_by_day(
     Ok(Json(res))
 }
 
+pub async fn get_browser_breakdown(
+    State(state): State<AppState>,
+    Path(fingerprint): Path<String>,
+    extract::Query(project): extract::Query<ProjectFilter>,
+) -> Result<Json<Vec<BrowserCount>>, (StatusCode, String)> {
+    let conn = state.duckdb_pool.get().map_err(internal_error)?;
+
+    let mut params: Vec<&dyn ToSql> = vec![&fingerprint];
+    let query = format!(
+        "
+        SELECT
+            browser_family,
+            browser_version,
+            os,
+            CAST(SUM(sample_weight) AS UBIGINT) as cnt
+        FROM csp_report
+        WHERE fingerprint = ? AND {}
+        GROUP BY 1, 2, 3
+        ORDER BY 4 DESC
+    ",
+        project.condition(&mut params)
+    );
+
+    let mut stmt = conn.prepare(&query).map_err(internal_error)?;
+    let res: Vec<BrowserCount> = stmt
+        .query_map(params_from_iter(params), |e| {
+            Ok(BrowserCount {
+                browser_family: e.get(0)?,
+                browser_version: e.get(1)?,
+                os: e.get(2)?,
+                cnt: e.get(3)?,
+            })
+        })
+        .map_err(internal_error)?
+        .collect::<Result<Vec<BrowserCount>, duckdb::Error>>()
+        .map_err(internal_error)?;
+
+    Ok(Json(res))
+}
+
 pub async fn get_reports(
     State(state): State<AppState>,
     extract::Query(query_params): extract::Query<GetReportQueryParams>,
+    extract::Query(project): extract::Query<ProjectFilter>,
 ) -> Result<Json<Vec<BufferItem>>, (StatusCode, String)> {
     let conn = state.duckdb_pool.get().map_err(internal_error)?;
 
-    let mut query = "
+    let mut params: Vec<&dyn ToSql> = vec![];
+    let mut query = format!(
+        "
         SELECT
             document_uri,
             CAST(created_at AS STRING),
@@ -188,11 +460,20 @@ pub async fn get_reports(
             source_file,
             status_code,
             script_sample,
-            source_ip
+            source_ip,
+            user_agent,
+            browser_family,
+            browser_version,
+            os,
+            sample_weight,
+            project_id,
+            blocked_uri_raw,
+            redacted
         FROM csp_report
-    "
-    .to_string();
-    let mut params: Vec<&dyn ToSql> = vec![];
+        WHERE {}
+    ",
+        project.condition(&mut params)
+    );
 
     if query_params.limit.is_some() {
         params.push(&query_params.limit);
@@ -221,6 +502,14 @@ pub async fn get_reports(
                 status_code: e.get(11)?,
                 script_sample: e.get(12)?,
                 source_ip: e.get(13)?,
+                user_agent: e.get(14)?,
+                browser_family: e.get(15)?,
+                browser_version: e.get(16)?,
+                os: e.get(17)?,
+                sample_weight: e.get(18)?,
+                project_id: e.get(19)?,
+                blocked_uri_raw: e.get(20)?,
+                redacted: e.get(21)?,
             })
         })
         .map_err(internal_error)?
@@ -230,29 +519,55 @@ pub async fn get_reports(
     Ok(Json(reports))
 }
 
+const DISTINCT_REPORT_COLUMNS: &str = "
+    fingerprint,
+    violated_directive,
+    effective_directive,
+    original_policy,
+    disposition,
+    blocked_uri,
+    source_file,
+    script_sample,
+    CAST(MIN(first_seen) AS STRING) as first_seen,
+    CAST(MAX(last_seen) AS STRING) as last_seen,
+    CAST(SUM(cnt) AS UBIGINT) as cnt
+";
+
+fn distinct_report_from_row(e: &duckdb::Row) -> Result<DistinctReport, duckdb::Error> {
+    Ok(DistinctReport {
+        fingerprint: e.get(0)?,
+        violated_directive: e.get(1)?,
+        effective_directive: e.get(2)?,
+        original_policy: e.get(3)?,
+        disposition: e.get(4)?,
+        blocked_uri: e.get(5)?,
+        source_file: e.get(6)?,
+        script_sample: e.get(7)?,
+        first_seen: e.get(8)?,
+        last_seen: e.get(9)?,
+        cnt: e.get(10)?,
+    })
+}
+
 pub async fn get_distinct_reports(
     State(state): State<AppState>,
     extract::Query(query_params): extract::Query<GetDistinctReportQueryParams>,
+    extract::Query(project): extract::Query<ProjectFilter>,
 ) -> Result<Json<Vec<DistinctReport>>, (StatusCode, String)> {
     let conn = state.duckdb_pool.get().map_err(internal_error)?;
 
-    let mut query = "
-        SELECT
-            violated_directive,
-            effective_directive,
-            original_policy,
-            disposition,
-            blocked_uri,
-            source_file,
-            script_sample,
-            CAST(MIN(created_at) AS STRING) as first_seen,
-            COUNT(*) as cnt
-        FROM csp_report
-        GROUP BY 1, 2, 3, 4, 5, 6, 7
-        ORDER BY 8 DESC
-    "
-    .to_string();
     let mut params: Vec<&dyn ToSql> = vec![];
+    let mut query = format!(
+        "
+        SELECT {}
+        FROM csp_report_rollup
+        WHERE {}
+        GROUP BY 1, 2, 3, 4, 5, 6, 7, 8
+        ORDER BY 9 DESC
+    ",
+        DISTINCT_REPORT_COLUMNS,
+        project.condition(&mut params)
+    );
 
     if query_params.limit.is_some() {
         params.push(&query_params.limit);
@@ -265,19 +580,7 @@ pub async fn get_distinct_reports(
 
     let mut stmt = conn.prepare(query.as_str()).map_err(internal_error)?;
     let reports: Vec<DistinctReport> = stmt
-        .query_map(params_from_iter(params), |e| {
-            Ok(DistinctReport {
-                violated_directive: e.get(0)?,
-                effective_directive: e.get(1)?,
-                original_policy: e.get(2)?,
-                disposition: e.get(3)?,
-                blocked_uri: e.get(4)?,
-                source_file: e.get(5)?,
-                script_sample: e.get(6)?,
-                first_seen: e.get(7)?,
-                cnt: e.get(8)?,
-            })
-        })
+        .query_map(params_from_iter(params), distinct_report_from_row)
         .map_err(internal_error)?
         .collect::<Result<Vec<DistinctReport>, duckdb::Error>>()
         .map_err(internal_error)?;
@@ -285,36 +588,348 @@ pub async fn get_distinct_reports(
     Ok(Json(reports))
 }
 
+pub async fn get_distinct_report(
+    State(state): State<AppState>,
+    Path(fingerprint): Path<String>,
+    extract::Query(project): extract::Query<ProjectFilter>,
+) -> Result<Json<DistinctReport>, (StatusCode, String)> {
+    let conn = state.duckdb_pool.get().map_err(internal_error)?;
+
+    let mut params: Vec<&dyn ToSql> = vec![&fingerprint];
+    let query = format!(
+        "
+        SELECT {}
+        FROM csp_report_rollup
+        WHERE fingerprint = ? AND {}
+        GROUP BY 1, 2, 3, 4, 5, 6, 7, 8
+    ",
+        DISTINCT_REPORT_COLUMNS,
+        project.condition(&mut params)
+    );
+
+    let mut stmt = conn.prepare(query.as_str()).map_err(internal_error)?;
+    let report = stmt
+        .query_map(params_from_iter(params), distinct_report_from_row)
+        .map_err(internal_error)?
+        .next()
+        .transpose()
+        .map_err(internal_error)?;
+
+    report
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Unknown fingerprint".to_string()))
+}
+
+/// Appends CSP reports and adds them to the rollup. Run inside a transaction,
+/// so the rollup never disagrees with the raw rows.
 pub fn append_buffer_items(
-    state: AppState,
-    buffer_items: Vec<BufferItem>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let conn = state.duckdb_pool.get()?;
+    conn: &duckdb::Connection,
+    buffer_items: &[BufferItem],
+) -> Result<(), duckdb::Error> {
     let mut app = conn.appender("csp_report")?;
 
-    let mut rows: Vec<[&dyn ToSql; 14]> = vec![];
+    let fingerprints: Vec<String> = buffer_items
+        .iter()
+        .map(|e| rollup::fingerprint(&rollup::grouping(e)))
+        .collect();
+    let mut rows: Vec<[&dyn ToSql; 23]> = vec![];
 
-    for i in 0..buffer_items.len() {
+    for (item, fingerprint) in buffer_items.iter().zip(fingerprints.iter()) {
         rows.push([
-            &buffer_items[i].source_ip as &dyn ToSql,
-            &buffer_items[i].created_at as &dyn ToSql,
-            &buffer_items[i].document_uri as &dyn ToSql,
-            &buffer_items[i].referrer as &dyn ToSql,
-            &buffer_items[i].violated_directive as &dyn ToSql,
-            &buffer_items[i].effective_directive as &dyn ToSql,
-            &buffer_items[i].original_policy as &dyn ToSql,
-            &buffer_items[i].disposition as &dyn ToSql,
-            &buffer_items[i].blocked_uri as &dyn ToSql,
-            &buffer_items[i].line_number as &dyn ToSql,
-            &buffer_items[i].column_number as &dyn ToSql,
-            &buffer_items[i].source_file as &dyn ToSql,
-            &buffer_items[i].status_code as &dyn ToSql,
-            &buffer_items[i].script_sample as &dyn ToSql,
+            &item.source_ip as &dyn ToSql,
+            &item.created_at as &dyn ToSql,
+            &item.document_uri as &dyn ToSql,
+            &item.referrer as &dyn ToSql,
+            &item.violated_directive as &dyn ToSql,
+            &item.effective_directive as &dyn ToSql,
+            &item.original_policy as &dyn ToSql,
+            &item.disposition as &dyn ToSql,
+            &item.blocked_uri as &dyn ToSql,
+            &item.line_number as &dyn ToSql,
+            &item.column_number as &dyn ToSql,
+            &item.source_file as &dyn ToSql,
+            &item.status_code as &dyn ToSql,
+            &item.script_sample as &dyn ToSql,
+            &item.user_agent as &dyn ToSql,
+            &item.browser_family as &dyn ToSql,
+            &item.browser_version as &dyn ToSql,
+            &item.os as &dyn ToSql,
+            &item.sample_weight as &dyn ToSql,
+            &item.project_id as &dyn ToSql,
+            &item.blocked_uri_raw as &dyn ToSql,
+            &item.redacted as &dyn ToSql,
+            fingerprint as &dyn ToSql,
         ]);
     }
-    if let Err(e) = app.append_rows(rows) {
-        error!("Error appending rows: {}", e);
+    app.append_rows(rows)?;
+    app.flush();
+    drop(app);
+    rollup::upsert(conn, buffer_items, &fingerprints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ingest::flush,
+        test_utils::{app_state, TempDir},
+    };
+
+    async fn ingest(state: &AppState, content_type: &str, body: &str) {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+        headers.insert(header::USER_AGENT, "Mozilla/5.0".parse().unwrap());
+        let client_ip = Some("203.0.113.7".parse().unwrap());
+        ingest_reports(
+            state.clone(),
+            None,
+            client_ip,
+            headers,
+            body.to_string().into(),
+        )
+        .await
+        .unwrap();
+        flush(state, false).await;
+    }
+
+    /// Stored rows with how long before now each was created, in seconds.
+    fn stored(state: &AppState) -> Vec<(BufferItem, i64)> {
+        let conn = state.duckdb_pool.get().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT document_uri, referrer, violated_directive, effective_directive,
+                    original_policy, disposition, blocked_uri, line_number, column_number,
+                    source_file, status_code, script_sample, source_ip, user_agent,
+                    CAST(created_at AS TEXT)
+                FROM csp_report ORDER BY document_uri",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map([], |e| {
+                Ok((
+                    BufferItem {
+                        document_uri: e.get(0)?,
+                        referrer: e.get(1)?,
+                        violated_directive: e.get(2)?,
+                        effective_directive: e.get(3)?,
+                        original_policy: e.get(4)?,
+                        disposition: e.get(5)?,
+                        blocked_uri: e.get(6)?,
+                        line_number: e.get(7)?,
+                        column_number: e.get(8)?,
+                        source_file: e.get(9)?,
+                        status_code: e.get(10)?,
+                        script_sample: e.get(11)?,
+                        source_ip: e.get(12)?,
+                        user_agent: e.get(13)?,
+                        ..Default::default()
+                    },
+                    e.get::<_, String>(14)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        rows.into_iter()
+            .map(|(item, created_at)| {
+                let created_at =
+                    chrono::NaiveDateTime::parse_from_str(&created_at, "%Y-%m-%d %H:%M:%S%.f")
+                        .unwrap();
+                (item, (now - created_at).num_seconds())
+            })
+            .collect()
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn batches_fan_out_into_one_row_per_report() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        ingest(
+            &state,
+            "application/reports+json",
+            r#"[
+                {
+                    "type": "csp-violation",
+                    "age": 0,
+                    "url": "https://example.com/a",
+                    "user_agent": "Chrome",
+                    "body": {
+                        "documentURL": "https://example.com/a",
+                        "referrer": "https://example.com/",
+                        "blockedURL": "https://cdn.example.com/x.js",
+                        "effectiveDirective": "script-src-elem",
+                        "originalPolicy": "script-src 'self'",
+                        "sourceFile": "https://example.com/app.js",
+                        "sample": "alert(1)",
+                        "disposition": "enforce",
+                        "statusCode": 200,
+                        "lineNumber": 3,
+                        "columnNumber": 7
+                    }
+                },
+                {
+                    "type": "csp-violation",
+                    "age": 0,
+                    "body": {"documentURL": "https://example.com/b", "blockedURL": "inline"}
+                }
+            ]"#,
+        )
+        .await;
+
+        let rows = stored(&state);
+        assert_eq!(rows.len(), 2);
+        let (a, _) = &rows[0];
+        assert_eq!(a.document_uri, "https://example.com/a");
+        assert_eq!(a.referrer, "https://example.com/");
+        assert_eq!(a.blocked_uri.as_deref(), Some("https://cdn.example.com"));
+        assert_eq!(a.violated_directive, "script-src-elem");
+        assert_eq!(a.effective_directive, "script-src-elem");
+        assert_eq!(a.original_policy, "script-src 'self'");
+        assert_eq!(a.disposition, "enforce");
+        assert_eq!(a.source_file.as_deref(), Some("https://example.com/app.js"));
+        assert_eq!(a.script_sample, "alert(1)");
+        assert_eq!(a.status_code, Some(200));
+        assert_eq!((a.line_number, a.column_number), (Some(3), Some(7)));
+        assert_eq!(a.source_ip, "203.0.113.7");
+        assert_eq!(a.user_agent, "Chrome");
+
+        let (b, _) = &rows[1];
+        assert_eq!(b.document_uri, "https://example.com/b");
+        assert_eq!(b.blocked_uri.as_deref(), Some("inline"));
+        // Falls back to the request's User-Agent.
+        assert_eq!(b.user_agent, "Mozilla/5.0");
+    }
+
+    #[tokio::test]
+    async fn age_backdates_created_at() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        ingest(
+            &state,
+            "application/reports+json",
+            &format!(
+                r#"[
+                    {{"type": "csp-violation", "age": 3600000, "body": {{"documentURL": "a"}}}},
+                    {{"type": "csp-violation", "age": {}, "body": {{"documentURL": "b"}}}}
+                ]"#,
+                i64::MAX
+            ),
+        )
+        .await;
+
+        let rows = stored(&state);
+        let ages: Vec<i64> = rows.iter().map(|e| e.1).collect();
+        assert!((3600..3660).contains(&ages[0]), "{:?}", ages);
+        // An age too large to subtract falls back to now.
+        assert!((0..60).contains(&ages[1]), "{:?}", ages);
+    }
+
+    #[tokio::test]
+    async fn malformed_reports_in_a_batch_are_skipped() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        ingest(
+            &state,
+            "application/reports+json",
+            r#"[
+                {"type": "csp-violation", "body": {"documentURL": "bad", "lineNumber": "x"}},
+                {"type": "csp-violation", "body": "not an object"},
+                {"type": "unknown-report", "body": {}},
+                {"type": "csp-violation", "body": {"documentURL": "good"}}
+            ]"#,
+        )
+        .await;
+
+        let uris: Vec<String> = stored(&state)
+            .into_iter()
+            .map(|e| e.0.document_uri)
+            .collect();
+        assert_eq!(uris, vec!["good"]);
+    }
+
+    #[tokio::test]
+    async fn legacy_reports_are_still_accepted() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        ingest(
+            &state,
+            "application/csp-report",
+            r#"{"csp-report": {
+                "document-uri": "https://example.com/",
+                "violated-directive": "img-src",
+                "effective-directive": "img-src",
+                "original-policy": "img-src 'none'",
+                "disposition": "report",
+                "blocked-uri": "https://img.example.com/a.png",
+                "script-sample": ""
+            }}"#,
+        )
+        .await;
+
+        let rows = stored(&state);
+        assert_eq!(rows.len(), 1);
+        let (report, age) = &rows[0];
+        assert_eq!(report.document_uri, "https://example.com/");
+        assert_eq!(report.violated_directive, "img-src");
+        assert_eq!(report.original_policy, "img-src 'none'");
+        assert_eq!(
+            report.blocked_uri.as_deref(),
+            Some("https://img.example.com")
+        );
+        assert_eq!(report.user_agent, "Mozilla/5.0");
+        assert!((0..60).contains(age));
+    }
+
+    #[tokio::test]
+    async fn browser_breakdown_is_keyed_on_the_fingerprint() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        let chrome = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/114.0.5735.199 Safari/537.36";
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/115.0";
+        let report = |user_agent: &str, blocked_url: &str| {
+            format!(
+                r#"{{"type": "csp-violation", "age": 0, "user_agent": "{}",
+                    "body": {{"documentURL": "https://example.com/", "blockedURL": "{}"}}}}"#,
+                user_agent, blocked_url
+            )
+        };
+        let batch = [
+            report(chrome, "inline"),
+            report(chrome, "inline"),
+            report(firefox, "inline"),
+            report(firefox, "eval"),
+        ];
+        ingest(
+            &state,
+            "application/reports+json",
+            &format!("[{}]", batch.join(",")),
+        )
+        .await;
+
+        let fingerprint = stored(&state)
+            .into_iter()
+            .find(|(e, _)| e.blocked_uri.as_deref() == Some("inline"))
+            .map(|(e, _)| rollup::fingerprint(&rollup::grouping(&e)))
+            .unwrap();
+        let breakdown = |fingerprint: &str| {
+            get_browser_breakdown(
+                State(state.clone()),
+                Path(fingerprint.to_string()),
+                extract::Query(ProjectFilter { project_id: None }),
+            )
+        };
+        let counts: Vec<(String, u64)> = breakdown(&fingerprint)
+            .await
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|e| (e.browser_family, e.cnt))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("Chrome".to_string(), 2), ("Firefox".to_string(), 1)]
+        );
+        assert!(breakdown("unknown").await.unwrap().0.is_empty());
+    }
 }
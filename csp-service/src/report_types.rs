@@ -0,0 +1,562 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{self, State},
+    http::StatusCode,
+    Json,
+};
+use duckdb::{appender_params_from_iter, params_from_iter, types::Value, ToSql};
+use serde::{Deserialize, Serialize};
+
+use crate::{project::ProjectFilter, state::AppState, utils::internal_error};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ColumnType {
+    Text,
+    UInteger,
+    Double,
+    Boolean,
+}
+
+/// A typed column filled from a field of the report body.
+#[derive(Debug)]
+pub struct Column {
+    pub name: &'static str,
+    pub field: &'static str,
+    pub column_type: ColumnType,
+}
+
+const fn column(name: &'static str, field: &'static str, column_type: ColumnType) -> Column {
+    Column {
+        name,
+        field,
+        column_type,
+    }
+}
+
+/// A Reporting API report type other than `csp-violation`, stored in its own
+/// table. Tables are created by the DuckDB migrations, keep `columns` in step.
+#[derive(Debug)]
+pub struct ReportType {
+    /// The `type` of the report as sent by the browser.
+    pub name: &'static str,
+    pub table: &'static str,
+    pub columns: &'static [Column],
+    /// The columns `get_distinct_typed_reports` groups on.
+    pub distinct_columns: &'static [&'static str],
+    /// The column `get_typed_report_count_by_day` breaks counts down by.
+    pub category_column: &'static str,
+    /// How the distinct and per day endpoints count rows.
+    pub count_expr: &'static str,
+    /// Body fields holding page URLs, redacted like `url`.
+    pub url_fields: &'static [&'static str],
+}
+
+/// Columns every report table starts with, in appender order.
+const COMMON_COLUMNS: [(&str, &str); 4] = [
+    ("source_ip", "sourceIp"),
+    ("created_at", "createdAt"),
+    ("url", "url"),
+    ("user_agent", "userAgent"),
+];
+
+pub const REPORT_TYPES: [ReportType; 7] = [
+    ReportType {
+        name: "coep",
+        table: "coep_report",
+        columns: &[
+            column("coep_type", "type", ColumnType::Text),
+            column("blocked_url", "blockedURL", ColumnType::Text),
+            column("destination", "destination", ColumnType::Text),
+            column("disposition", "disposition", ColumnType::Text),
+        ],
+        distinct_columns: &["coep_type", "blocked_url", "destination", "disposition"],
+        category_column: "coep_type",
+        count_expr: "COUNT(*)",
+        url_fields: &["blockedURL"],
+    },
+    ReportType {
+        name: "coop",
+        table: "coop_report",
+        columns: &[
+            column("coop_type", "type", ColumnType::Text),
+            column("disposition", "disposition", ColumnType::Text),
+            column("effective_policy", "effectivePolicy", ColumnType::Text),
+            column("property", "property", ColumnType::Text),
+            column(
+                "previous_response_url",
+                "previousResponseURL",
+                ColumnType::Text,
+            ),
+            column("next_response_url", "nextResponseURL", ColumnType::Text),
+            column("other_document_url", "otherDocumentURL", ColumnType::Text),
+            column("referrer", "referrer", ColumnType::Text),
+            column("source_file", "sourceFile", ColumnType::Text),
+            column("line_number", "lineNumber", ColumnType::UInteger),
+            column("column_number", "columnNumber", ColumnType::UInteger),
+        ],
+        distinct_columns: &["coop_type", "effective_policy", "disposition", "property"],
+        category_column: "coop_type",
+        count_expr: "COUNT(*)",
+        url_fields: &[
+            "previousResponseURL",
+            "nextResponseURL",
+            "otherDocumentURL",
+            "referrer",
+        ],
+    },
+    ReportType {
+        name: "permissions-policy-violation",
+        table: "permissions_policy_report",
+        columns: &[
+            column("feature_id", "featureId", ColumnType::Text),
+            column("disposition", "disposition", ColumnType::Text),
+            column("message", "message", ColumnType::Text),
+            column("source_file", "sourceFile", ColumnType::Text),
+            column("line_number", "lineNumber", ColumnType::UInteger),
+            column("column_number", "columnNumber", ColumnType::UInteger),
+        ],
+        distinct_columns: &["feature_id", "disposition", "source_file"],
+        category_column: "feature_id",
+        count_expr: "COUNT(*)",
+        url_fields: &[],
+    },
+    ReportType {
+        name: "deprecation",
+        table: "deprecation_report",
+        columns: &[
+            column("deprecation_id", "id", ColumnType::Text),
+            column(
+                "anticipated_removal",
+                "anticipatedRemoval",
+                ColumnType::Text,
+            ),
+            column("message", "message", ColumnType::Text),
+            column("source_file", "sourceFile", ColumnType::Text),
+            column("line_number", "lineNumber", ColumnType::UInteger),
+            column("column_number", "columnNumber", ColumnType::UInteger),
+        ],
+        distinct_columns: &["deprecation_id", "message", "source_file"],
+        category_column: "deprecation_id",
+        count_expr: "COUNT(*)",
+        url_fields: &[],
+    },
+    ReportType {
+        name: "intervention",
+        table: "intervention_report",
+        columns: &[
+            column("intervention_id", "id", ColumnType::Text),
+            column("message", "message", ColumnType::Text),
+            column("source_file", "sourceFile", ColumnType::Text),
+            column("line_number", "lineNumber", ColumnType::UInteger),
+            column("column_number", "columnNumber", ColumnType::UInteger),
+        ],
+        distinct_columns: &["intervention_id", "message", "source_file"],
+        category_column: "intervention_id",
+        count_expr: "COUNT(*)",
+        url_fields: &[],
+    },
+    ReportType {
+        name: "crash",
+        table: "crash_report",
+        columns: &[
+            column("reason", "reason", ColumnType::Text),
+            column("stack", "stack", ColumnType::Text),
+            column("is_top_level", "is_top_level", ColumnType::Boolean),
+            column("visibility_state", "visibility_state", ColumnType::Text),
+        ],
+        distinct_columns: &["reason", "visibility_state"],
+        category_column: "reason",
+        count_expr: "COUNT(*)",
+        url_fields: &[],
+    },
+    ReportType {
+        name: "network-error",
+        table: "nel_report",
+        columns: &[
+            column("referrer", "referrer", ColumnType::Text),
+            column("sampling_fraction", "sampling_fraction", ColumnType::Double),
+            column("server_ip", "server_ip", ColumnType::Text),
+            column("protocol", "protocol", ColumnType::Text),
+            column("method", "method", ColumnType::Text),
+            column("request_headers", "request_headers", ColumnType::Text),
+            column("response_headers", "response_headers", ColumnType::Text),
+            column("status_code", "status_code", ColumnType::UInteger),
+            column("elapsed_time", "elapsed_time", ColumnType::UInteger),
+            column("phase", "phase", ColumnType::Text),
+            column("nel_type", "type", ColumnType::Text),
+        ],
+        distinct_columns: &["nel_type", "phase", "server_ip", "status_code"],
+        category_column: "nel_type",
+        // Each report stands for `1 / sampling_fraction` requests.
+        count_expr: "CAST(ROUND(SUM(1.0 / COALESCE(NULLIF(sampling_fraction, 0), 1))) AS UBIGINT)",
+        url_fields: &["referrer"],
+    },
+];
+
+pub fn find_report_type(name: &str) -> Option<&'static ReportType> {
+    REPORT_TYPES.iter().find(|e| e.name == name)
+}
+
+fn report_type_or_404(name: &str) -> Result<&'static ReportType, (StatusCode, String)> {
+    find_report_type(name).ok_or((
+        StatusCode::NOT_FOUND,
+        format!("Unknown report type \"{}\"", name),
+    ))
+}
+
+impl ReportType {
+    fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.iter().find(|e| e.name == name)
+    }
+
+    /// The JSON key a column is returned under, the body field for typed columns.
+    /// The camelCase key a column is returned under, named after its body field.
+    fn json_key(&self, name: &str) -> String {
+        COMMON_COLUMNS
+            .iter()
+            .find(|(column, _)| *column == name)
+            .map(|(_, key)| key.to_string())
+            .or_else(|| self.column(name).map(|e| camel_case(e.field)))
+            .unwrap_or_default()
+    }
+}
+
+/// Some report bodies use snake_case fields, e.g. `elapsed_time` in NEL reports.
+fn camel_case(field: &str) -> String {
+    let mut parts = field.split('_');
+    let mut key = parts.next().unwrap_or_default().to_owned();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            key.extend(first.to_uppercase());
+            key.push_str(chars.as_str());
+        }
+    }
+    key
+}
+
+/// A queued report of one of the `REPORT_TYPES`, with its body kept as sent.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypedReport {
+    pub report_type: String,
+    pub created_at: String,
+    pub url: String,
+    pub user_agent: String,
+    pub source_ip: String,
+    pub body: serde_json::Value,
+    #[serde(default)]
+    pub project_id: Option<i64>,
+}
+
+fn body_value(body: &serde_json::Value, column: &Column) -> Value {
+    let field = match body.get(column.field) {
+        None | Some(serde_json::Value::Null) => return Value::Null,
+        Some(e) => e,
+    };
+    match column.column_type {
+        ColumnType::Text => match field.as_str() {
+            Some(e) => Value::Text(e.to_owned()),
+            None => Value::Text(field.to_string()),
+        },
+        ColumnType::UInteger => field
+            .as_u64()
+            .and_then(|e| u32::try_from(e).ok())
+            .map_or(Value::Null, Value::UInt),
+        ColumnType::Double => field.as_f64().map_or(Value::Null, Value::Double),
+        ColumnType::Boolean => field.as_bool().map_or(Value::Null, Value::Boolean),
+    }
+}
+
+fn json_value(value: Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Boolean(e) => e.into(),
+        Value::UInt(e) => e.into(),
+        Value::UBigInt(e) => e.into(),
+        Value::Int(e) => e.into(),
+        Value::BigInt(e) => e.into(),
+        Value::Double(e) => e.into(),
+        Value::Text(e) => e.into(),
+        e => format!("{:?}", e).into(),
+    }
+}
+
+pub fn append_typed_reports(
+    conn: &duckdb::Connection,
+    reports: &[TypedReport],
+) -> Result<(), duckdb::Error> {
+    let mut by_type: HashMap<&'static str, (&'static ReportType, Vec<&TypedReport>)> =
+        HashMap::new();
+    for report in reports {
+        if let Some(report_type) = find_report_type(&report.report_type) {
+            by_type
+                .entry(report_type.name)
+                .or_insert((report_type, vec![]))
+                .1
+                .push(report);
+        }
+    }
+
+    for (report_type, reports) in by_type.into_values() {
+        let mut app = conn.appender(report_type.table)?;
+        for report in reports {
+            let mut row = vec![
+                Value::Text(report.source_ip.clone()),
+                Value::Text(report.created_at.clone()),
+                Value::Text(report.url.clone()),
+                Value::Text(report.user_agent.clone()),
+            ];
+            row.extend(
+                report_type
+                    .columns
+                    .iter()
+                    .map(|column| body_value(&report.body, column)),
+            );
+            row.push(report.project_id.map_or(Value::Null, Value::BigInt));
+            app.append_row(appender_params_from_iter(row))?;
+        }
+        app.flush();
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetTypedReportQueryParams {
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypedReportCount {
+    pub day: String,
+    pub category: Option<String>,
+    pub cnt: u64,
+}
+
+fn query_rows(
+    state: &AppState,
+    query: &str,
+    params: Vec<&dyn ToSql>,
+    keys: &[String],
+) -> Result<Vec<serde_json::Map<String, serde_json::Value>>, (StatusCode, String)> {
+    let conn = state.duckdb_pool.get().map_err(internal_error)?;
+    let mut stmt = conn.prepare(query).map_err(internal_error)?;
+    let rows = stmt
+        .query_map(params_from_iter(params), |e| {
+            let mut row = serde_json::Map::new();
+            for (i, key) in keys.iter().enumerate() {
+                row.insert(key.clone(), json_value(e.get(i)?));
+            }
+            Ok(row)
+        })
+        .map_err(internal_error)?
+        .collect::<Result<Vec<_>, duckdb::Error>>()
+        .map_err(internal_error)?;
+    Ok(rows)
+}
+
+fn push_pagination<'a>(
+    query: &mut String,
+    params: &mut Vec<&'a dyn ToSql>,
+    query_params: &'a GetTypedReportQueryParams,
+) {
+    if query_params.limit.is_some() {
+        params.push(&query_params.limit);
+        query.push_str(" LIMIT ?")
+    }
+    if query_params.offset.is_some() {
+        params.push(&query_params.offset);
+        query.push_str(" OFFSET ?")
+    }
+}
+
+pub async fn get_typed_reports(
+    State(state): State<AppState>,
+    extract::Path(name): extract::Path<String>,
+    extract::Query(query_params): extract::Query<GetTypedReportQueryParams>,
+    extract::Query(project): extract::Query<ProjectFilter>,
+) -> Result<Json<Vec<serde_json::Map<String, serde_json::Value>>>, (StatusCode, String)> {
+    let report_type = report_type_or_404(&name)?;
+
+    let mut columns: Vec<&str> = COMMON_COLUMNS.iter().map(|(e, _)| *e).collect();
+    columns.extend(report_type.columns.iter().map(|e| e.name));
+    let select: Vec<String> = columns
+        .iter()
+        .map(|e| match *e {
+            "created_at" => "CAST(created_at AS STRING)".to_string(),
+            e => e.to_string(),
+        })
+        .collect();
+    let keys: Vec<String> = columns.iter().map(|e| report_type.json_key(e)).collect();
+
+    let mut params: Vec<&dyn ToSql> = vec![];
+    let mut query = format!(
+        "SELECT {} FROM {} WHERE {} ORDER BY created_at DESC",
+        select.join(", "),
+        report_type.table,
+        project.condition(&mut params)
+    );
+    push_pagination(&mut query, &mut params, &query_params);
+
+    Ok(Json(query_rows(&state, &query, params, &keys)?))
+}
+
+pub async fn get_distinct_typed_reports(
+    State(state): State<AppState>,
+    extract::Path(name): extract::Path<String>,
+    extract::Query(query_params): extract::Query<GetTypedReportQueryParams>,
+    extract::Query(project): extract::Query<ProjectFilter>,
+) -> Result<Json<Vec<serde_json::Map<String, serde_json::Value>>>, (StatusCode, String)> {
+    let report_type = report_type_or_404(&name)?;
+
+    let group_by: Vec<String> = (1..=report_type.distinct_columns.len())
+        .map(|e| e.to_string())
+        .collect();
+    let mut keys: Vec<String> = report_type
+        .distinct_columns
+        .iter()
+        .map(|e| report_type.json_key(e))
+        .collect();
+    keys.extend(["firstSeen".to_string(), "cnt".to_string()]);
+
+    let mut params: Vec<&dyn ToSql> = vec![];
+    let mut query = format!(
+        "SELECT {}, CAST(MIN(created_at) AS STRING) as first_seen, {} as cnt
+        FROM {}
+        WHERE {}
+        GROUP BY {}
+        ORDER BY {} DESC",
+        report_type.distinct_columns.join(", "),
+        report_type.count_expr,
+        report_type.table,
+        project.condition(&mut params),
+        group_by.join(", "),
+        report_type.distinct_columns.len() + 1
+    );
+    push_pagination(&mut query, &mut params, &query_params);
+
+    Ok(Json(query_rows(&state, &query, params, &keys)?))
+}
+
+pub async fn get_typed_report_count_by_day(
+    State(state): State<AppState>,
+    extract::Path(name): extract::Path<String>,
+    extract::Query(project): extract::Query<ProjectFilter>,
+) -> Result<Json<Vec<TypedReportCount>>, (StatusCode, String)> {
+    let report_type = report_type_or_404(&name)?;
+    let conn = state.duckdb_pool.get().map_err(internal_error)?;
+
+    let mut params: Vec<&dyn ToSql> = vec![];
+    let query = format!(
+        "SELECT
+            CAST(CAST(created_at AS DATE) AS TEXT) AS day,
+            CAST({} AS TEXT) AS category,
+            {} AS cnt
+        FROM {}
+        WHERE created_at >= CURRENT_DATE - INTERVAL 13 DAY AND {}
+        GROUP BY 1, 2
+        ORDER BY 1 ASC, 3 DESC",
+        report_type.category_column,
+        report_type.count_expr,
+        report_type.table,
+        project.condition(&mut params)
+    );
+
+    let mut stmt = conn.prepare(&query).map_err(internal_error)?;
+    let res: Vec<TypedReportCount> = stmt
+        .query_map(params_from_iter(params), |e| {
+            Ok(TypedReportCount {
+                day: e.get(0)?,
+                category: e.get(1)?,
+                cnt: e.get(2)?,
+            })
+        })
+        .map_err(internal_error)?
+        .collect::<Result<Vec<TypedReportCount>, duckdb::Error>>()
+        .map_err(internal_error)?;
+
+    Ok(Json(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::migrated_duckdb;
+
+    #[test]
+    fn reads_body_values() {
+        let body = serde_json::json!({
+            "text": "a",
+            "nested": {"b": 1},
+            "number": 7,
+            "negative": -1,
+            "fraction": 0.25,
+            "flag": true,
+            "missing": null,
+        });
+        let value = |field, column_type| body_value(&body, &column("c", field, column_type));
+        assert_eq!(
+            value("text", ColumnType::Text),
+            Value::Text("a".to_string())
+        );
+        assert_eq!(
+            value("nested", ColumnType::Text),
+            Value::Text(r#"{"b":1}"#.to_string())
+        );
+        assert_eq!(value("number", ColumnType::UInteger), Value::UInt(7));
+        assert_eq!(value("negative", ColumnType::UInteger), Value::Null);
+        assert_eq!(value("fraction", ColumnType::Double), Value::Double(0.25));
+        assert_eq!(value("flag", ColumnType::Boolean), Value::Boolean(true));
+        assert_eq!(value("text", ColumnType::Boolean), Value::Null);
+        assert_eq!(value("missing", ColumnType::Text), Value::Null);
+        assert_eq!(value("absent", ColumnType::Text), Value::Null);
+    }
+
+    #[test]
+    fn json_keys_follow_body_fields() {
+        let coop = find_report_type("coop").unwrap();
+        assert_eq!(coop.json_key("created_at"), "createdAt");
+        assert_eq!(coop.json_key("next_response_url"), "nextResponseURL");
+        let nel = find_report_type("network-error").unwrap();
+        assert_eq!(nel.json_key("elapsed_time"), "elapsedTime");
+        assert_eq!(nel.json_key("nel_type"), "type");
+        let crash = find_report_type("crash").unwrap();
+        assert_eq!(crash.json_key("is_top_level"), "isTopLevel");
+        for report_type in REPORT_TYPES.iter() {
+            for column in report_type.columns {
+                assert!(!report_type.json_key(column.name).contains('_'));
+            }
+        }
+        assert!(find_report_type("csp-violation").is_none());
+    }
+
+    #[test]
+    fn migrated_tables_match_columns() {
+        let conn = migrated_duckdb();
+        let mut stmt = conn
+            .prepare(
+                "SELECT column_name FROM duckdb_columns()
+                WHERE table_name = ? ORDER BY column_index",
+            )
+            .unwrap();
+        for report_type in REPORT_TYPES.iter() {
+            let columns: Vec<String> = stmt
+                .query_map([report_type.table], |e| e.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            let expected: Vec<&str> = COMMON_COLUMNS
+                .iter()
+                .map(|e| e.0)
+                .chain(report_type.columns.iter().map(|e| e.name))
+                .chain(["project_id"])
+                .collect();
+            assert_eq!(columns, expected, "{}", report_type.table);
+        }
+    }
+}
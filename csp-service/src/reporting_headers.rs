@@ -0,0 +1,168 @@
+use axum::{
+    extract::{self, State},
+    http::StatusCode,
+    Json,
+};
+use deadpool_sqlite::rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{state::AppState, utils::internal_error};
+
+const GROUP_DEFAULT: &str = "metlo-csp";
+const MAX_AGE_DEFAULT: u32 = 30 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetReportingHeadersQueryParams {
+    pub project_id: Option<i64>,
+    pub group: Option<String>,
+    pub max_age: Option<u32>,
+}
+
+/// Header values pointing browsers at this service.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportingHeaders {
+    pub endpoint: String,
+    /// Directives to append to `Content-Security-Policy` or
+    /// `Content-Security-Policy-Report-Only`. `report-uri` covers browsers
+    /// without Reporting API support.
+    pub content_security_policy: String,
+    pub reporting_endpoints: String,
+    /// The legacy `Report-To` header, still needed for NEL.
+    pub report_to: String,
+}
+
+async fn project_key(state: &AppState, project_id: i64) -> Result<String, (StatusCode, String)> {
+    let db_conn = state.db_pool.get().await.map_err(internal_error)?;
+    let key: Option<String> = db_conn
+        .interact(move |conn| {
+            conn.query_row("SELECT key FROM project WHERE id = ?", [project_id], |e| {
+                e.get(0)
+            })
+            .optional()
+        })
+        .await
+        .map_err(internal_error)?
+        .map_err(internal_error)?;
+    key.ok_or((StatusCode::NOT_FOUND, "Unknown project".to_string()))
+}
+
+/// Builds the header values for `public_url`, pointing at the project's
+/// ingest path when `project_key` is set.
+fn reporting_headers(
+    public_url: Option<&str>,
+    project_key: Option<&str>,
+    group: Option<String>,
+    max_age: Option<u32>,
+) -> Result<ReportingHeaders, (StatusCode, String)> {
+    let public_url = public_url.ok_or((
+        StatusCode::CONFLICT,
+        "METLO_PUBLIC_URL is not set".to_string(),
+    ))?;
+    let group = group.unwrap_or(GROUP_DEFAULT.to_string());
+    // The group ends up unquoted in the CSP and as a structured header key.
+    let valid_group = group.starts_with(|e: char| e.is_ascii_lowercase())
+        && group
+            .chars()
+            .all(|e| e.is_ascii_lowercase() || e.is_ascii_digit() || "_-".contains(e));
+    if !valid_group {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Group must be lowercase letters, digits, \"-\" or \"_\"".to_string(),
+        ));
+    }
+
+    let endpoint = match project_key {
+        Some(key) => format!("{}/r/{}", public_url, key),
+        None => format!("{}/", public_url),
+    };
+    let report_to = json!({
+        "group": group,
+        "max_age": max_age.unwrap_or(MAX_AGE_DEFAULT),
+        "endpoints": [{ "url": endpoint }],
+    });
+
+    Ok(ReportingHeaders {
+        content_security_policy: format!("report-uri {}; report-to {}", endpoint, group),
+        reporting_endpoints: format!("{}=\"{}\"", group, endpoint),
+        report_to: report_to.to_string(),
+        endpoint,
+    })
+}
+
+pub async fn get_reporting_headers(
+    State(state): State<AppState>,
+    extract::Query(query_params): extract::Query<GetReportingHeadersQueryParams>,
+) -> Result<Json<ReportingHeaders>, (StatusCode, String)> {
+    let project_key = match query_params.project_id {
+        Some(project_id) => Some(project_key(&state, project_id).await?),
+        None => None,
+    };
+    reporting_headers(
+        state.public_url.as_deref(),
+        project_key.as_deref(),
+        query_params.group,
+        query_params.max_age,
+    )
+    .map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_public_url() {
+        let (status, _) = reporting_headers(None, None, None, None).unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn points_at_the_project_path() {
+        let headers =
+            reporting_headers(Some("https://csp.example.com"), Some("abc123"), None, None).unwrap();
+        assert_eq!(headers.endpoint, "https://csp.example.com/r/abc123");
+        assert_eq!(
+            headers.content_security_policy,
+            "report-uri https://csp.example.com/r/abc123; report-to metlo-csp"
+        );
+        assert_eq!(
+            headers.reporting_endpoints,
+            "metlo-csp=\"https://csp.example.com/r/abc123\""
+        );
+    }
+
+    #[test]
+    fn report_to_uses_group_and_max_age() {
+        let headers = reporting_headers(
+            Some("https://csp.example.com"),
+            None,
+            Some("nel-1".to_string()),
+            Some(3600),
+        )
+        .unwrap();
+        let report_to: serde_json::Value = serde_json::from_str(&headers.report_to).unwrap();
+        assert_eq!(
+            report_to,
+            json!({
+                "group": "nel-1",
+                "max_age": 3600,
+                "endpoints": [{ "url": "https://csp.example.com/" }],
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_group() {
+        let (status, _) = reporting_headers(
+            Some("https://csp.example.com"),
+            None,
+            Some("Bad Group".to_string()),
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
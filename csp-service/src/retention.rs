@@ -0,0 +1,520 @@
+use std::{collections::HashMap, env, sync::Mutex, time::Duration};
+
+use axum::{extract::State, http::StatusCode, Json};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::{report_types::REPORT_TYPES, rollup, state::AppState, utils::internal_error};
+
+const INTERVAL_SECS_DEFAULT: u64 = 60 * 60;
+/// Share of a table's rows that have to be deleted before it is vacuumed.
+const VACUUM_DELETED_SHARE: f64 = 0.25;
+
+/// Runs of the pruner since startup.
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionRuns {
+    pub last_run_at: Option<String>,
+    pub last_rows_removed: u64,
+    pub last_error: Option<String>,
+    pub total_rows_removed: u64,
+}
+
+/// Limits on how much report data is kept. Every report table is pruned on
+/// its own, oldest rows first.
+#[derive(Debug)]
+pub struct Retention {
+    max_age_days: Option<u32>,
+    max_rows: Option<u64>,
+    max_bytes: Option<u64>,
+    interval: Duration,
+    /// Rows deleted from each table since it was last vacuumed.
+    deleted: Mutex<HashMap<&'static str, u64>>,
+    runs: Mutex<RetentionRuns>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionStatus {
+    pub max_age_days: Option<u32>,
+    pub max_rows: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub database_bytes: u64,
+    #[serde(flatten)]
+    pub runs: RetentionRuns,
+}
+
+fn limit_from_env<T: std::str::FromStr>(name: &str) -> Result<Option<T>, String> {
+    match env::var(name) {
+        Ok(e) if !e.is_empty() => e
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid {}: {}", name, e)),
+        _ => Ok(None),
+    }
+}
+
+fn report_tables() -> impl Iterator<Item = &'static str> {
+    std::iter::once("csp_report").chain(REPORT_TYPES.iter().map(|e| e.table))
+}
+
+/// Bytes in use by the database file, only accurate after a checkpoint.
+fn database_bytes(conn: &duckdb::Connection) -> Result<u64, duckdb::Error> {
+    conn.query_row(
+        "SELECT CAST(used_blocks * block_size AS UBIGINT) FROM pragma_database_size()",
+        [],
+        |e| e.get(0),
+    )
+}
+
+/// Holds off the flusher while rows are deleted from `csp_report`. Both update
+/// the rollup rows of the same distinct reports, and DuckDB aborts one of two
+/// transactions writing the same row.
+fn block_conflicting_writes<'a>(
+    state: &'a AppState,
+    table: &str,
+) -> Option<tokio::sync::MutexGuard<'a, ()>> {
+    (table == "csp_report").then(|| state.ingest.block_writes())
+}
+
+impl Retention {
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Retention {
+            max_age_days: limit_from_env("METLO_RETENTION_DAYS")?,
+            max_rows: limit_from_env("METLO_RETENTION_MAX_ROWS")?,
+            max_bytes: limit_from_env("METLO_RETENTION_MAX_BYTES")?,
+            interval: Duration::from_secs(
+                limit_from_env("METLO_RETENTION_INTERVAL_SECS")?
+                    .unwrap_or(INTERVAL_SECS_DEFAULT)
+                    .max(1),
+            ),
+            deleted: Mutex::new(HashMap::new()),
+            runs: Mutex::new(RetentionRuns::default()),
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_age_days.is_some() || self.max_rows.is_some() || self.max_bytes.is_some()
+    }
+
+    fn add_deleted(&self, table: &'static str, rows: u64) -> u64 {
+        *self.deleted.lock().unwrap().entry(table).or_default() += rows;
+        rows
+    }
+
+    /// When reports expire by age, none do when that is before the earliest
+    /// representable time.
+    fn cutoff(&self) -> Option<String> {
+        let days = self.max_age_days?;
+        let cutoff = chrono::Utc::now().checked_sub_signed(chrono::Duration::days(days.into()))?;
+        Some(cutoff.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+    }
+
+    /// Deletes the rows of `table` matching `condition`. When raw CSP reports
+    /// go, the rollup rows of their distinct reports are recomputed from the
+    /// rows left in the same transaction, so the distinct reports view keeps
+    /// agreeing with the raw table.
+    fn delete<P: duckdb::Params>(
+        &self,
+        conn: &mut duckdb::Connection,
+        table: &'static str,
+        condition: &str,
+        params: P,
+    ) -> Result<u64, duckdb::Error> {
+        let tx = conn.transaction()?;
+        let removed = if table == "csp_report" {
+            tx.execute(
+                &format!(
+                    "CREATE TEMP TABLE pruned_reports AS
+                    SELECT rowid AS id, project_id, fingerprint FROM csp_report WHERE {condition}"
+                ),
+                params,
+            )?;
+            let removed = tx.execute(
+                "DELETE FROM csp_report WHERE rowid IN (SELECT id FROM pruned_reports)",
+                [],
+            )?;
+            rollup::recompute(&tx, "pruned_reports")?;
+            tx.execute_batch("DROP TABLE pruned_reports;")?;
+            removed
+        } else {
+            tx.execute(&format!("DELETE FROM {table} WHERE {condition}"), params)?
+        };
+        tx.commit()?;
+        Ok(self.add_deleted(table, removed as u64))
+    }
+
+    /// Deletes rows past the age and row limits.
+    fn expire(
+        &self,
+        conn: &mut duckdb::Connection,
+        table: &'static str,
+    ) -> Result<u64, duckdb::Error> {
+        let mut removed = 0;
+        if let Some(cutoff) = self.cutoff() {
+            removed += self.delete(conn, table, "created_at < CAST(? AS TIMESTAMP)", [cutoff])?;
+        }
+        if let Some(max_rows) = self.max_rows {
+            removed += self.delete(
+                conn,
+                table,
+                &format!(
+                    "rowid IN (
+                        SELECT rowid FROM {table} ORDER BY created_at DESC OFFSET ?
+                    )"
+                ),
+                [max_rows],
+            )?;
+        }
+        Ok(removed)
+    }
+
+    /// Deletes the oldest `fraction` of a table's rows.
+    fn expire_fraction(
+        &self,
+        conn: &mut duckdb::Connection,
+        table: &'static str,
+        fraction: f64,
+    ) -> Result<u64, duckdb::Error> {
+        self.delete(
+            conn,
+            table,
+            &format!(
+                "rowid IN (
+                    SELECT rowid FROM {table} ORDER BY created_at ASC
+                    LIMIT CAST(CEIL((SELECT COUNT(*) FROM {table}) * ?) AS UBIGINT)
+                )"
+            ),
+            [fraction],
+        )
+    }
+
+    /// Rebuilds tables once `min_share` of their rows have been deleted, then
+    /// checkpoints. DuckDB only reuses the space of deleted rows after the table
+    /// holding them is dropped, so the rows left are copied into a new one.
+    ///
+    /// DuckDB crashes when a table is dropped while another connection queries
+    /// it, and refuses to checkpoint with other transactions open, so this
+    /// holds off the flusher and takes every other pooled connection first.
+    fn vacuum(
+        &self,
+        state: &AppState,
+        conn: &mut duckdb::Connection,
+        min_share: f64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let _writes = state.ingest.block_writes();
+        let _conns = (1..state.duckdb_pool.max_size())
+            .map(|_| state.duckdb_pool.get())
+            .collect::<Result<Vec<_>, _>>()?;
+        for table in report_tables() {
+            let deleted = self
+                .deleted
+                .lock()
+                .unwrap()
+                .get(table)
+                .copied()
+                .unwrap_or(0);
+            if deleted == 0 {
+                continue;
+            }
+            let remaining: u64 = conn.query_row(
+                &format!("SELECT CAST(COUNT(*) AS UBIGINT) FROM {}", table),
+                [],
+                |e| e.get(0),
+            )?;
+            if (deleted as f64) < remaining as f64 * min_share {
+                continue;
+            }
+            let create_sql: String = conn.query_row(
+                "SELECT sql FROM duckdb_tables() WHERE table_name = ?",
+                [table],
+                |e| e.get(0),
+            )?;
+            let tx = conn.transaction()?;
+            tx.execute_batch(&format!(
+                "ALTER TABLE {table} RENAME TO {table}_vacuum;
+                {create_sql}
+                INSERT INTO {table} SELECT * FROM {table}_vacuum;
+                DROP TABLE {table}_vacuum;"
+            ))?;
+            tx.commit()?;
+            self.deleted.lock().unwrap().remove(table);
+        }
+        conn.execute_batch("CHECKPOINT;")?;
+        Ok(())
+    }
+
+    fn prune(&self, state: &AppState) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let mut conn = state.duckdb_pool.get()?;
+        let mut removed = 0;
+        for table in report_tables() {
+            let _writes = block_conflicting_writes(state, table);
+            removed += self.expire(&mut conn, table)?;
+        }
+        self.vacuum(state, &mut conn, VACUUM_DELETED_SHARE)?;
+        if let Some(max_bytes) = self.max_bytes {
+            if database_bytes(&conn)? > max_bytes {
+                // Rows deleted but not yet vacuumed would overstate the size.
+                self.vacuum(state, &mut conn, 0.0)?;
+                let used = database_bytes(&conn)?;
+                if used > max_bytes {
+                    // Rows are roughly the same size, so removing the share of
+                    // rows the database is over by gets it close. What's left
+                    // over is picked up by the next run.
+                    let fraction = 1.0 - max_bytes as f64 / used as f64;
+                    for table in report_tables() {
+                        let _writes = block_conflicting_writes(state, table);
+                        removed += self.expire_fraction(&mut conn, table, fraction)?;
+                    }
+                    self.vacuum(state, &mut conn, 0.0)?;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+pub async fn run_pruner(state: AppState) {
+    if !state.retention.is_enabled() {
+        return;
+    }
+    let mut interval = tokio::time::interval(state.retention.interval);
+    loop {
+        interval.tick().await;
+        let res = tokio::task::spawn_blocking({
+            let state = state.clone();
+            move || state.retention.prune(&state).map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+
+        let mut runs = state.retention.runs.lock().unwrap();
+        runs.last_run_at =
+            Some(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
+        match res {
+            Ok(removed) => {
+                if removed > 0 {
+                    info!("Retention removed {} expired reports", removed);
+                }
+                runs.last_rows_removed = removed;
+                runs.total_rows_removed += removed;
+                runs.last_error = None;
+            }
+            Err(e) => {
+                error!("Error pruning reports: {}", e);
+                runs.last_rows_removed = 0;
+                runs.last_error = Some(e);
+            }
+        }
+    }
+}
+
+pub async fn get_retention(
+    State(state): State<AppState>,
+) -> Result<Json<RetentionStatus>, (StatusCode, String)> {
+    let conn = state.duckdb_pool.get().map_err(internal_error)?;
+    let retention = &state.retention;
+    Ok(Json(RetentionStatus {
+        max_age_days: retention.max_age_days,
+        max_rows: retention.max_rows,
+        max_bytes: retention.max_bytes,
+        database_bytes: database_bytes(&conn).map_err(internal_error)?,
+        runs: retention.runs.lock().unwrap().clone(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{app_state, migrated_duckdb, TempDir};
+
+    fn retention(max_rows: Option<u64>) -> Retention {
+        limits(None, max_rows, None)
+    }
+
+    fn limits(
+        max_age_days: Option<u32>,
+        max_rows: Option<u64>,
+        max_bytes: Option<u64>,
+    ) -> Retention {
+        Retention {
+            max_age_days,
+            max_rows,
+            max_bytes,
+            interval: Duration::from_secs(INTERVAL_SECS_DEFAULT),
+            deleted: Mutex::new(HashMap::new()),
+            runs: Mutex::new(RetentionRuns::default()),
+        }
+    }
+
+    fn insert(conn: &duckdb::Connection, created_at: &str, weight: u32, fingerprint: &str) {
+        conn.execute(
+            "INSERT INTO csp_report (
+                source_ip, created_at, document_uri, referrer, violated_directive,
+                effective_directive, original_policy, disposition, script_sample,
+                sample_weight, fingerprint
+            ) VALUES ('', CAST(? AS TIMESTAMP), '', '', ?, ?, '', 'report', '', ?, ?)",
+            duckdb::params![created_at, fingerprint, fingerprint, weight, fingerprint],
+        )
+        .unwrap();
+    }
+
+    fn rollup_rows(conn: &duckdb::Connection) -> Vec<(String, String, u64)> {
+        let mut stmt = conn
+            .prepare(
+                "SELECT fingerprint, CAST(first_seen AS TEXT), cnt
+                FROM csp_report_rollup ORDER BY 1",
+            )
+            .unwrap();
+        stmt.query_map([], |e| Ok((e.get(0)?, e.get(1)?, e.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn count(conn: &duckdb::Connection, table: &str) -> u64 {
+        conn.query_row(
+            &format!("SELECT CAST(COUNT(*) AS UBIGINT) FROM {}", table),
+            [],
+            |e| e.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn age_limit_removes_old_reports() {
+        let mut conn = migrated_duckdb();
+        let now = chrono::Utc::now();
+        for days_ago in [0, 5, 40] {
+            let created_at = (now - chrono::Duration::days(days_ago)).format("%Y-%m-%d %H:%M:%S");
+            insert(&conn, &created_at.to_string(), 1, &days_ago.to_string());
+        }
+        conn.execute(
+            "INSERT INTO nel_report (source_ip, created_at, url, user_agent)
+            VALUES ('', CURRENT_DATE - INTERVAL 40 DAY, '', '')",
+            [],
+        )
+        .unwrap();
+        rollup::rebuild(&mut conn).unwrap();
+
+        let retention = limits(Some(30), None, None);
+        assert_eq!(retention.expire(&mut conn, "csp_report").unwrap(), 1);
+        assert_eq!(retention.expire(&mut conn, "nel_report").unwrap(), 1);
+        assert_eq!(count(&conn, "csp_report"), 2);
+        assert_eq!(count(&conn, "nel_report"), 0);
+        let fingerprints: Vec<String> = rollup_rows(&conn).into_iter().map(|e| e.0).collect();
+        assert_eq!(fingerprints, vec!["0", "5"]);
+    }
+
+    #[test]
+    fn huge_age_limits_expire_nothing() {
+        let mut conn = migrated_duckdb();
+        insert(&conn, "2000-01-01 00:00:00", 1, "a");
+        let retention = limits(Some(u32::MAX), None, None);
+        assert_eq!(retention.cutoff(), None);
+        assert_eq!(retention.expire(&mut conn, "csp_report").unwrap(), 0);
+    }
+
+    #[test]
+    fn row_limit_keeps_the_newest_reports() {
+        let mut conn = migrated_duckdb();
+        for day in 1..=5 {
+            insert(
+                &conn,
+                &format!("2024-01-0{} 00:00:00", day),
+                1,
+                &day.to_string(),
+            );
+        }
+        rollup::rebuild(&mut conn).unwrap();
+        assert_eq!(
+            retention(Some(3)).expire(&mut conn, "csp_report").unwrap(),
+            2
+        );
+        let fingerprints: Vec<String> = rollup_rows(&conn).into_iter().map(|e| e.0).collect();
+        assert_eq!(fingerprints, vec!["3", "4", "5"]);
+    }
+
+    #[tokio::test]
+    async fn byte_limit_removes_the_oldest_reports() {
+        let dir = TempDir::new();
+        let state = app_state(dir.path()).await;
+        let used = {
+            let conn = state.duckdb_pool.get().unwrap();
+            conn.execute_batch(
+                "INSERT INTO csp_report (
+                    source_ip, created_at, document_uri, referrer, violated_directive,
+                    effective_directive, original_policy, disposition, script_sample
+                )
+                SELECT '', TIMESTAMP '2024-01-01' + to_seconds(i), md5(CAST(i AS TEXT)) || repeat('x', 200),
+                    '', '', '', '', 'report', ''
+                FROM range(50000) t(i);
+                CHECKPOINT;",
+            )
+            .unwrap();
+            database_bytes(&conn).unwrap()
+        };
+
+        let retention = limits(None, None, Some(used / 2));
+        let (removed, state) = tokio::task::spawn_blocking(move || {
+            let removed = retention.prune(&state).unwrap();
+            (removed, state)
+        })
+        .await
+        .unwrap();
+        assert!(removed > 0);
+        let conn = state.duckdb_pool.get().unwrap();
+        assert_eq!(count(&conn, "csp_report"), 50000 - removed);
+        assert!(database_bytes(&conn).unwrap() < used);
+        // The oldest rows went first.
+        let oldest: String = conn
+            .query_row(
+                "SELECT CAST(MIN(created_at) AS TEXT) FROM csp_report",
+                [],
+                |e| e.get(0),
+            )
+            .unwrap();
+        let expected = chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            + chrono::Duration::seconds(removed as i64);
+        assert_eq!(oldest, expected.format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+
+    #[test]
+    fn row_limit_recomputes_rollup() {
+        let mut conn = migrated_duckdb();
+        insert(&conn, "2024-01-01 00:00:00", 1, "a");
+        insert(&conn, "2024-01-02 00:00:00", 2, "a");
+        insert(&conn, "2024-01-03 00:00:00", 3, "a");
+        insert(&conn, "2024-01-03 00:00:01", 1, "b");
+        rollup::rebuild(&mut conn).unwrap();
+
+        let removed = retention(Some(2)).expire(&mut conn, "csp_report").unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(
+            rollup_rows(&conn),
+            vec![
+                ("a".to_string(), "2024-01-03 00:00:00".to_string(), 3),
+                ("b".to_string(), "2024-01-03 00:00:01".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn fraction_removes_distinct_reports_left_without_rows() {
+        let mut conn = migrated_duckdb();
+        insert(&conn, "2024-01-01 00:00:00", 5, "a");
+        insert(&conn, "2024-01-02 00:00:00", 1, "b");
+        rollup::rebuild(&mut conn).unwrap();
+
+        retention(None)
+            .expire_fraction(&mut conn, "csp_report", 0.5)
+            .unwrap();
+        assert_eq!(
+            rollup_rows(&conn),
+            vec![("b".to_string(), "2024-01-02 00:00:00".to_string(), 1)]
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+
+use duckdb::params;
+use sha2::{Digest, Sha256};
+
+use crate::report::BufferItem;
+
+/// Per distinct report totals in `csp_report_rollup`, kept up to date as
+/// reports are appended so the distinct reports view doesn't have to group the
+/// whole raw table. Selected from `csp_report` in the column order of the table.
+const ROLLUP_SELECT: &str = "
+    SELECT
+        project_id,
+        violated_directive,
+        effective_directive,
+        original_policy,
+        disposition,
+        blocked_uri,
+        source_file,
+        script_sample,
+        MIN(created_at),
+        MAX(created_at),
+        CAST(SUM(sample_weight) AS UBIGINT),
+        fingerprint
+    FROM csp_report
+";
+const ROLLUP_GROUP_BY: &str = "GROUP BY 1, 2, 3, 4, 5, 6, 7, 8, 12";
+
+/// The fields distinct reports are grouped on.
+pub type Grouping<'a> = (
+    &'a str,
+    &'a str,
+    &'a str,
+    &'a str,
+    Option<&'a str>,
+    Option<&'a str>,
+    &'a str,
+);
+
+pub fn grouping(item: &BufferItem) -> Grouping<'_> {
+    (
+        &item.violated_directive,
+        &item.effective_directive,
+        &item.original_policy,
+        &item.disposition,
+        item.blocked_uri.as_deref(),
+        item.source_file.as_deref(),
+        &item.script_sample,
+    )
+}
+
+/// Identifies a distinct report across restarts and releases: the first 16
+/// bytes of a SHA-256 over its grouping fields, hex encoded. Each field is
+/// length prefixed, and missing ones are told apart from empty ones, so
+/// different groupings can't run together into the same input.
+pub fn fingerprint(grouping: &Grouping) -> String {
+    let (violated, effective, policy, disposition, blocked, source, sample) = *grouping;
+    let mut hasher = Sha256::new();
+    for field in [
+        Some(violated),
+        Some(effective),
+        Some(policy),
+        Some(disposition),
+        blocked,
+        source,
+        Some(sample),
+    ] {
+        match field {
+            Some(e) => {
+                hasher.update([1]);
+                hasher.update((e.len() as u64).to_le_bytes());
+                hasher.update(e);
+            }
+            None => hasher.update([0]),
+        }
+    }
+    hasher.finalize()[..16]
+        .iter()
+        .map(|e| format!("{:02x}", e))
+        .collect()
+}
+
+struct RollupEntry<'a> {
+    grouping: Grouping<'a>,
+    first_seen: &'a str,
+    last_seen: &'a str,
+    cnt: u64,
+}
+
+/// Adds appended reports to their rollup rows, creating the rows of reports
+/// seen for the first time. Meant to run in the transaction that appends them,
+/// with `fingerprints` matching `items`.
+pub fn upsert(
+    conn: &duckdb::Connection,
+    items: &[BufferItem],
+    fingerprints: &[String],
+) -> Result<(), duckdb::Error> {
+    let mut entries: HashMap<(Option<i64>, &str), RollupEntry> = HashMap::new();
+    for (item, fingerprint) in items.iter().zip(fingerprints) {
+        // Timestamps share one RFC 3339 format, so they compare as strings.
+        let entry = entries
+            .entry((item.project_id, fingerprint))
+            .or_insert(RollupEntry {
+                grouping: grouping(item),
+                first_seen: &item.created_at,
+                last_seen: &item.created_at,
+                cnt: 0,
+            });
+        entry.first_seen = entry.first_seen.min(&item.created_at);
+        entry.last_seen = entry.last_seen.max(&item.created_at);
+        entry.cnt += item.sample_weight as u64;
+    }
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    // Staging the batch lets it be applied with a single join each way instead
+    // of a scan of the rollup per distinct report.
+    conn.execute_batch(
+        "CREATE TEMP TABLE rollup_batch AS SELECT * FROM csp_report_rollup LIMIT 0;",
+    )?;
+    {
+        let mut insert = conn.prepare(
+            "INSERT INTO rollup_batch VALUES (
+                ?, ?, ?, ?, ?, ?, ?, ?, CAST(? AS TIMESTAMP), CAST(? AS TIMESTAMP), ?, ?
+            )",
+        )?;
+        for ((project_id, fingerprint), entry) in entries {
+            let (violated, effective, policy, disposition, blocked, source, sample) =
+                entry.grouping;
+            insert.execute(params![
+                project_id,
+                violated,
+                effective,
+                policy,
+                disposition,
+                blocked,
+                source,
+                sample,
+                entry.first_seen,
+                entry.last_seen,
+                entry.cnt,
+                fingerprint,
+            ])?;
+        }
+    }
+    conn.execute_batch(
+        "UPDATE csp_report_rollup SET
+            first_seen = LEAST(csp_report_rollup.first_seen, b.first_seen),
+            last_seen = GREATEST(csp_report_rollup.last_seen, b.last_seen),
+            cnt = csp_report_rollup.cnt + b.cnt
+        FROM rollup_batch b
+        WHERE csp_report_rollup.project_id IS NOT DISTINCT FROM b.project_id
+            AND csp_report_rollup.fingerprint = b.fingerprint;
+        INSERT INTO csp_report_rollup
+        SELECT * FROM rollup_batch b
+        WHERE NOT EXISTS (
+            SELECT 1 FROM csp_report_rollup r
+            WHERE r.project_id IS NOT DISTINCT FROM b.project_id
+                AND r.fingerprint = b.fingerprint
+        );
+        DROP TABLE rollup_batch;",
+    )
+}
+
+/// Fills in the fingerprints of reports stored before they were computed at
+/// ingest, returning how many distinct reports were missing one. Runs on
+/// startup and is a no-op once every row has its fingerprint.
+pub fn backfill_fingerprints(conn: &mut duckdb::Connection) -> Result<u64, duckdb::Error> {
+    let tx = conn.transaction()?;
+    let missing = {
+        let select = |table: &str| {
+            format!(
+                "SELECT
+                    violated_directive,
+                    effective_directive,
+                    original_policy,
+                    disposition,
+                    blocked_uri,
+                    source_file,
+                    script_sample
+                FROM {table}
+                WHERE fingerprint IS NULL"
+            )
+        };
+        let mut stmt = tx.prepare(&format!(
+            "{} UNION {}",
+            select("csp_report"),
+            select("csp_report_rollup")
+        ))?;
+        stmt.query_map([], |e| {
+            Ok((
+                e.get::<_, String>(0)?,
+                e.get::<_, String>(1)?,
+                e.get::<_, String>(2)?,
+                e.get::<_, String>(3)?,
+                e.get::<_, Option<String>>(4)?,
+                e.get::<_, Option<String>>(5)?,
+                e.get::<_, String>(6)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?
+    };
+    if missing.is_empty() {
+        return Ok(0);
+    }
+
+    tx.execute_batch(
+        "CREATE TEMP TABLE fingerprint_backfill (
+            violated_directive TEXT,
+            effective_directive TEXT,
+            original_policy TEXT,
+            disposition TEXT,
+            blocked_uri TEXT,
+            source_file TEXT,
+            script_sample TEXT,
+            fingerprint TEXT
+        );",
+    )?;
+    {
+        let mut insert =
+            tx.prepare("INSERT INTO fingerprint_backfill VALUES (?, ?, ?, ?, ?, ?, ?, ?)")?;
+        for row in missing.iter() {
+            let grouping = (
+                row.0.as_str(),
+                row.1.as_str(),
+                row.2.as_str(),
+                row.3.as_str(),
+                row.4.as_deref(),
+                row.5.as_deref(),
+                row.6.as_str(),
+            );
+            insert.execute(params![
+                row.0,
+                row.1,
+                row.2,
+                row.3,
+                row.4,
+                row.5,
+                row.6,
+                fingerprint(&grouping),
+            ])?;
+        }
+    }
+    for table in ["csp_report", "csp_report_rollup"] {
+        tx.execute_batch(&format!(
+            "UPDATE {table} SET fingerprint = b.fingerprint
+            FROM fingerprint_backfill b
+            WHERE {table}.fingerprint IS NULL
+                AND {table}.violated_directive = b.violated_directive
+                AND {table}.effective_directive = b.effective_directive
+                AND {table}.original_policy = b.original_policy
+                AND {table}.disposition = b.disposition
+                AND {table}.blocked_uri IS NOT DISTINCT FROM b.blocked_uri
+                AND {table}.source_file IS NOT DISTINCT FROM b.source_file
+                AND {table}.script_sample = b.script_sample;"
+        ))?;
+    }
+    tx.execute_batch("DROP TABLE fingerprint_backfill;")?;
+    tx.commit()?;
+    Ok(missing.len() as u64)
+}
+
+/// Recomputes the rollup rows of the distinct reports listed in `keys`, a
+/// table with `project_id` and `fingerprint` columns, from the raw reports left.
+/// Meant to run in the transaction that deleted raw reports.
+pub fn recompute(conn: &duckdb::Connection, keys: &str) -> Result<(), duckdb::Error> {
+    conn.execute_batch(&format!(
+        "DELETE FROM csp_report_rollup
+        WHERE EXISTS (
+            SELECT 1 FROM {keys} k
+            WHERE csp_report_rollup.project_id IS NOT DISTINCT FROM k.project_id
+                AND csp_report_rollup.fingerprint = k.fingerprint
+        );
+        INSERT INTO csp_report_rollup
+        {ROLLUP_SELECT}
+        WHERE EXISTS (
+            SELECT 1 FROM {keys} k
+            WHERE csp_report.project_id IS NOT DISTINCT FROM k.project_id
+                AND csp_report.fingerprint = k.fingerprint
+        )
+        {ROLLUP_GROUP_BY};"
+    ))
+}
+
+/// Recomputes the rollup from the raw reports, returning how many distinct
+/// reports it holds.
+pub fn rebuild(conn: &mut duckdb::Connection) -> Result<u64, duckdb::Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(&format!(
+        "DELETE FROM csp_report_rollup;
+        INSERT INTO csp_report_rollup {ROLLUP_SELECT} {ROLLUP_GROUP_BY};"
+    ))?;
+    let rows: u64 = tx.query_row(
+        "SELECT CAST(COUNT(*) AS UBIGINT) FROM csp_report_rollup",
+        [],
+        |e| e.get(0),
+    )?;
+    tx.commit()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::migrated_duckdb;
+
+    fn item(
+        project_id: Option<i64>,
+        blocked_uri: &str,
+        created_at: &str,
+        weight: u32,
+    ) -> BufferItem {
+        BufferItem {
+            violated_directive: "script-src".to_string(),
+            effective_directive: "script-src".to_string(),
+            disposition: "enforce".to_string(),
+            blocked_uri: Some(blocked_uri.to_string()),
+            created_at: created_at.to_string(),
+            sample_weight: weight,
+            project_id,
+            ..Default::default()
+        }
+    }
+
+    fn upsert_items(conn: &duckdb::Connection, items: &[BufferItem]) {
+        let fingerprints: Vec<String> = items.iter().map(|e| fingerprint(&grouping(e))).collect();
+        upsert(conn, items, &fingerprints).unwrap();
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        let item = item(None, "https://example.com", "", 1);
+        // Changing this value breaks links to existing distinct reports.
+        assert_eq!(
+            fingerprint(&grouping(&item)),
+            "93ed64ccbdee09d64d9927b73affb73e"
+        );
+    }
+
+    #[test]
+    fn fingerprint_keeps_fields_apart() {
+        let grouping = ("a", "b", "", "", None, None, "");
+        assert_ne!(
+            fingerprint(&grouping),
+            fingerprint(&("ab", "", "", "", None, None, ""))
+        );
+        assert_ne!(
+            fingerprint(&grouping),
+            fingerprint(&("a", "b", "", "", Some(""), None, ""))
+        );
+        assert_ne!(
+            fingerprint(&("a", "b", "", "", Some(""), None, "")),
+            fingerprint(&("a", "b", "", "", None, Some(""), ""))
+        );
+    }
+
+    #[test]
+    fn upsert_adds_to_existing_rows() {
+        let conn = migrated_duckdb();
+        upsert_items(
+            &conn,
+            &[
+                item(None, "https://a.com", "2024-01-02T00:00:00.000Z", 1),
+                item(None, "https://a.com", "2024-01-03T00:00:00.000Z", 2),
+                item(Some(1), "https://a.com", "2024-01-02T00:00:00.000Z", 1),
+            ],
+        );
+        upsert_items(
+            &conn,
+            &[
+                item(None, "https://a.com", "2024-01-01T00:00:00.000Z", 4),
+                item(None, "https://b.com", "2024-01-04T00:00:00.000Z", 1),
+            ],
+        );
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT project_id, blocked_uri, CAST(first_seen AS TEXT), CAST(last_seen AS TEXT), cnt
+                FROM csp_report_rollup ORDER BY 1 NULLS FIRST, 2",
+            )
+            .unwrap();
+        let rows: Vec<(Option<i64>, String, String, String, u64)> = stmt
+            .query_map([], |e| {
+                Ok((e.get(0)?, e.get(1)?, e.get(2)?, e.get(3)?, e.get(4)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    None,
+                    "https://a.com".to_string(),
+                    "2024-01-01 00:00:00".to_string(),
+                    "2024-01-03 00:00:00".to_string(),
+                    7
+                ),
+                (
+                    None,
+                    "https://b.com".to_string(),
+                    "2024-01-04 00:00:00".to_string(),
+                    "2024-01-04 00:00:00".to_string(),
+                    1
+                ),
+                (
+                    Some(1),
+                    "https://a.com".to_string(),
+                    "2024-01-02 00:00:00".to_string(),
+                    "2024-01-02 00:00:00".to_string(),
+                    1
+                ),
+            ]
+        );
+    }
+}
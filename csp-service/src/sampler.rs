@@ -0,0 +1,189 @@
+use std::{collections::HashMap, env};
+
+use crate::{report::BufferItem, rollup};
+
+const SAMPLING_RATE_DEFAULT: u32 = 100;
+
+/// Identifies the distinct report an item belongs to by its rollup
+/// fingerprint, per project.
+type DistinctReportKey = (Option<i64>, String);
+
+fn distinct_report_key(item: &BufferItem) -> DistinctReportKey {
+    (
+        item.project_id,
+        rollup::fingerprint(&rollup::grouping(item)),
+    )
+}
+
+fn current_hour() -> i64 {
+    chrono::Utc::now().timestamp() / 3600
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    hour: i64,
+    seen: u64,
+    /// The row standing in for every report skipped since the last stored sample.
+    held: Option<BufferItem>,
+}
+
+/// Caps how many raw rows a single distinct report stores per hour.
+///
+/// The first `threshold` reports of a distinct report in an hour are stored
+/// as is. After that only one row per `rate` reports is stored, with a
+/// `sample_weight` equal to the number of reports it stands for, so summing
+/// the weights still gives exact counts.
+#[derive(Debug)]
+pub struct Sampler {
+    threshold: u64,
+    rate: u32,
+    windows: HashMap<DistinctReportKey, Window>,
+}
+
+impl Sampler {
+    pub fn from_env() -> Self {
+        let threshold: u64 = env::var("METLO_SAMPLING_THRESHOLD")
+            .unwrap_or("0".to_string())
+            .parse()
+            .unwrap_or(0);
+        let rate: u32 = env::var("METLO_SAMPLING_RATE")
+            .unwrap_or(SAMPLING_RATE_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(SAMPLING_RATE_DEFAULT);
+        Sampler::new(threshold, rate)
+    }
+
+    /// A `threshold` of 0 disables sampling.
+    pub fn new(threshold: u64, rate: u32) -> Self {
+        Sampler {
+            threshold,
+            rate: rate.max(1),
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Returns the items that should be stored. The number of reports folded
+    /// into a held row is added to `skipped`.
+    pub fn sample(&mut self, items: Vec<BufferItem>, skipped: &mut u64) -> Vec<BufferItem> {
+        if self.threshold == 0 {
+            return items;
+        }
+        let hour = current_hour();
+        let mut res = vec![];
+        for item in items {
+            let window = self.windows.entry(distinct_report_key(&item)).or_default();
+            if window.hour != hour {
+                res.extend(window.held.take());
+                window.hour = hour;
+                window.seen = 0;
+            }
+            window.seen += 1;
+            if window.seen <= self.threshold {
+                res.push(item);
+                continue;
+            }
+            match window.held.as_mut() {
+                Some(held) => {
+                    held.sample_weight += item.sample_weight;
+                    *skipped += 1;
+                }
+                None => window.held = Some(item),
+            }
+            if window.held.as_ref().map_or(0, |e| e.sample_weight) >= self.rate {
+                res.extend(window.held.take());
+            }
+        }
+        res
+    }
+
+    /// The rows currently standing in for skipped reports.
+    pub fn held(&self) -> impl Iterator<Item = &BufferItem> {
+        self.windows.values().filter_map(|e| e.held.as_ref())
+    }
+
+    /// Releases the held rows of windows from past hours, or of every window
+    /// when `all` is set, so their counts are not lost.
+    pub fn release_held(&mut self, all: bool) -> Vec<BufferItem> {
+        let hour = current_hour();
+        let mut res = vec![];
+        self.windows.retain(|_, window| {
+            if all || window.hour < hour {
+                res.extend(window.held.take());
+                return false;
+            }
+            true
+        });
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reports(n: usize, blocked_uri: &str) -> Vec<BufferItem> {
+        (0..n)
+            .map(|_| BufferItem {
+                blocked_uri: Some(blocked_uri.to_string()),
+                sample_weight: 1,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn weight(items: &[BufferItem]) -> u32 {
+        items.iter().map(|e| e.sample_weight).sum()
+    }
+
+    #[test]
+    fn disabled_keeps_everything() {
+        let mut sampler = Sampler::new(0, 10);
+        let mut skipped = 0;
+        assert_eq!(sampler.sample(reports(50, "a"), &mut skipped).len(), 50);
+        assert_eq!(skipped, 0);
+    }
+
+    #[test]
+    fn weights_add_up_to_exact_counts() {
+        let mut sampler = Sampler::new(5, 10);
+        let mut skipped = 0;
+        let mut stored = sampler.sample(reports(32, "a"), &mut skipped);
+        // 5 under the threshold, then 2 full samples of 10 with 7 held back.
+        assert_eq!(stored.len(), 7);
+        assert!(stored[5..].iter().all(|e| e.sample_weight == 10));
+        assert_eq!(skipped, 32 - 5 - 3);
+
+        stored.extend(sampler.release_held(true));
+        assert_eq!(weight(&stored), 32);
+        assert!(sampler.windows.is_empty());
+    }
+
+    #[test]
+    fn distinct_reports_are_sampled_apart() {
+        let mut sampler = Sampler::new(1, 100);
+        let mut skipped = 0;
+        let mut batch = reports(3, "a");
+        batch.extend(reports(3, "b"));
+        let mut stored = sampler.sample(batch, &mut skipped);
+        assert_eq!(stored.len(), 2);
+
+        stored.extend(sampler.release_held(true));
+        assert_eq!(stored.len(), 4);
+        assert_eq!(weight(&stored), 6);
+    }
+
+    #[test]
+    fn projects_are_sampled_apart() {
+        let mut sampler = Sampler::new(1, 100);
+        let mut skipped = 0;
+        let mut batch = reports(3, "a");
+        batch.extend(reports(3, "a").into_iter().map(|e| BufferItem {
+            project_id: Some(1),
+            ..e
+        }));
+        let stored = sampler.sample(batch, &mut skipped);
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[1].project_id, Some(1));
+        assert_eq!(sampler.release_held(true).len(), 2);
+    }
+}
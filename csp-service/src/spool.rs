@@ -0,0 +1,255 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+
+use crate::ingest::{append_ingest_items, IngestItem};
+use crate::state::AppState;
+
+const SPOOL_FILE_NAME: &str = "reports.spool";
+const HELD_FILE_NAME: &str = "reports.held";
+
+/// An append-only log of every report that has been acknowledged but not yet
+/// written to DuckDB. Each line is a JSON encoded `IngestItem`.
+///
+/// Whenever the flusher drains the ingest queue it rotates the active file into
+/// a numbered segment and removes that segment once DuckDB has the rows. Any
+/// file left behind is replayed on the next startup, including one whose rows
+/// made it to DuckDB just before a crash, so those are stored twice.
+///
+/// Reports the sampler folds into a held row aren't stored when their segment
+/// is removed, so the held rows are saved next to the spool after every flush
+/// and replayed along with it.
+pub struct Spool {
+    dir: PathBuf,
+    file: File,
+    next_segment: u64,
+}
+
+impl Spool {
+    /// Opens the spool in `dir`. An active file left by a previous run is
+    /// moved into a segment so `replay` picks it up.
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        let mut next_segment = segment_paths(dir)?
+            .iter()
+            .filter_map(|e| segment_number(e))
+            .max()
+            .map_or(0, |e| e + 1);
+        let active = dir.join(SPOOL_FILE_NAME);
+        if active.exists() {
+            fs::rename(
+                &active,
+                dir.join(format!("{}.{}", SPOOL_FILE_NAME, next_segment)),
+            )?;
+            next_segment += 1;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(active)?;
+        Ok(Spool {
+            dir: dir.to_path_buf(),
+            file,
+            next_segment,
+        })
+    }
+
+    /// Appends JSON encoded `IngestItem`s in a single write. A failed write is
+    /// cut back off so none of the lines are replayed and the next ones don't
+    /// run into what part of them made it to disk.
+    pub fn append(&mut self, lines: &[&[u8]]) -> std::io::Result<()> {
+        let mut buf = Vec::with_capacity(lines.iter().map(|e| e.len() + 1).sum());
+        for line in lines {
+            buf.extend_from_slice(line);
+            buf.push(b'\n');
+        }
+        let len = self.file.metadata()?.len();
+        self.file.write_all(&buf).inspect_err(|_| {
+            let _ = self.file.set_len(len);
+        })
+    }
+
+    /// Moves everything written so far into a new segment and starts an empty
+    /// active file. Returns the path of the segment.
+    pub fn rotate(&mut self) -> std::io::Result<PathBuf> {
+        let segment = self
+            .dir
+            .join(format!("{}.{}", SPOOL_FILE_NAME, self.next_segment));
+        self.next_segment += 1;
+        fs::rename(self.dir.join(SPOOL_FILE_NAME), &segment)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(SPOOL_FILE_NAME))?;
+        Ok(segment)
+    }
+
+    /// Replaces the saved held sample rows with `lines`, JSON encoded
+    /// `IngestItem`s. The file is swapped in whole so a crash leaves either the
+    /// old rows or the new ones.
+    pub fn save_held(&self, lines: &[Vec<u8>]) -> std::io::Result<()> {
+        let path = self.dir.join(HELD_FILE_NAME);
+        if lines.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let mut buf = vec![];
+        for line in lines {
+            buf.extend_from_slice(line);
+            buf.push(b'\n');
+        }
+        let tmp = self.dir.join(format!("{}.tmp", HELD_FILE_NAME));
+        fs::write(&tmp, buf)?;
+        fs::rename(tmp, path)
+    }
+}
+
+fn segment_number(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix(SPOOL_FILE_NAME)?
+        .strip_prefix('.')?
+        .parse()
+        .ok()
+}
+
+fn segment_paths(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|e| segment_number(e).is_some())
+        .collect();
+    paths.sort_by_key(|e| segment_number(e));
+    Ok(paths)
+}
+
+fn read_spool_file(path: &Path) -> std::io::Result<Vec<IngestItem>> {
+    let mut items = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(item) => items.push(item),
+            // The last line may be cut short if the process died mid write.
+            Err(e) => warn!("Skipping corrupt spool entry in {:?}: {}", path, e),
+        }
+    }
+    Ok(items)
+}
+
+/// Writes every report left in the spool by a previous run to DuckDB, along
+/// with the sample rows it was holding. Must run before the flusher starts
+/// rotating segments.
+pub fn replay(
+    state: &AppState,
+    dir: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut paths = segment_paths(dir)?;
+    let held = dir.join(HELD_FILE_NAME);
+    if held.exists() {
+        paths.push(held);
+    }
+    for path in paths {
+        let items = read_spool_file(&path)?;
+        if !items.is_empty() {
+            info!("Replaying {} spooled reports from {:?}", items.len(), path);
+            append_ingest_items(state.clone(), items)?;
+        }
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{report::BufferItem, report_types::TypedReport, test_utils::TempDir};
+
+    fn line(document_uri: &str) -> Vec<u8> {
+        serde_json::to_vec(&BufferItem {
+            document_uri: document_uri.to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn document_uris(path: &Path) -> Vec<String> {
+        read_spool_file(path)
+            .unwrap()
+            .into_iter()
+            .map(|e| match e {
+                IngestItem::Csp(e) => e.document_uri,
+                IngestItem::Typed(e) => e.url,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rotates_into_numbered_segments() {
+        let dir = TempDir::new();
+        let mut spool = Spool::open(dir.path()).unwrap();
+        spool.append(&[&line("a")]).unwrap();
+        spool.append(&[&line("b")]).unwrap();
+        let first = spool.rotate().unwrap();
+        spool.append(&[&line("c")]).unwrap();
+        let second = spool.rotate().unwrap();
+
+        assert_eq!(
+            segment_paths(dir.path()).unwrap(),
+            vec![first.clone(), second.clone()]
+        );
+        assert_eq!(document_uris(&first), vec!["a", "b"]);
+        assert_eq!(document_uris(&second), vec!["c"]);
+        assert!(document_uris(&dir.path().join(SPOOL_FILE_NAME)).is_empty());
+    }
+
+    #[test]
+    fn reopening_keeps_unflushed_reports_for_replay() {
+        let dir = TempDir::new();
+        let mut spool = Spool::open(dir.path()).unwrap();
+        spool.append(&[&line("a")]).unwrap();
+        spool.rotate().unwrap();
+        spool.append(&[&line("b")]).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(dir.path()).unwrap();
+        let segments = segment_paths(dir.path()).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(document_uris(&segments[0]), vec!["a"]);
+        assert_eq!(document_uris(&segments[1]), vec!["b"]);
+        // New segments are numbered after the ones left behind.
+        assert_eq!(segment_number(&spool.rotate().unwrap()), Some(2));
+    }
+
+    #[test]
+    fn skips_a_line_cut_short() {
+        let dir = TempDir::new();
+        let path = dir.path().join(format!("{}.0", SPOOL_FILE_NAME));
+        let mut contents = line("a");
+        contents.extend_from_slice(b"\n{\"documentUri\":\"b");
+        fs::write(&path, contents).unwrap();
+        assert_eq!(document_uris(&path), vec!["a"]);
+    }
+
+    #[test]
+    fn reads_back_csp_and_typed_reports() {
+        let dir = TempDir::new();
+        let path = dir.path().join(format!("{}.0", SPOOL_FILE_NAME));
+        let typed = IngestItem::Typed(TypedReport {
+            report_type: "crash".to_string(),
+            ..Default::default()
+        });
+        let mut contents = line("a");
+        contents.push(b'\n');
+        contents.extend(serde_json::to_vec(&typed).unwrap());
+        fs::write(&path, contents).unwrap();
+
+        let items = read_spool_file(&path).unwrap();
+        assert!(matches!(&items[0], IngestItem::Csp(e) if e.document_uri == "a"));
+        assert!(matches!(&items[1], IngestItem::Typed(e) if e.report_type == "crash"));
+    }
+}
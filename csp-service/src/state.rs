@@ -1,29 +1,97 @@
-use std::{env, fs, path::Path};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use deadpool_sqlite::{Config, Pool as SQLitePool, Runtime};
 use duckdb::DuckdbConnectionManager;
-use r2d2;
+use ipnet::IpNet;
+use log::info;
+
+use crate::{
+    client_ip::parse_trusted_proxies,
+    filter::NoiseFilter,
+    ingest::IngestQueue,
+    metrics::Metrics,
+    migrations::{migrate_duckdb, migrate_sqlite},
+    payload::MAX_BODY_BYTES_DEFAULT,
+    project::ProjectKeys,
+    rate_limit::RateLimiter,
+    redact::Redactor,
+    retention::Retention,
+    rollup, spool,
+};
 
 #[derive(Clone)]
 pub struct AppState {
     pub db_pool: SQLitePool,
     pub duckdb_pool: r2d2::Pool<DuckdbConnectionManager>,
     pub secret_key: String,
+    pub trusted_proxies: Arc<Vec<IpNet>>,
+    pub ingest: IngestQueue,
+    pub metrics: Arc<Metrics>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub max_body_bytes: usize,
+    pub noise_filter: Arc<NoiseFilter>,
+    pub project_keys: ProjectKeys,
+    pub redactor: Arc<Redactor>,
+    pub retention: Arc<Retention>,
+    /// The URL browsers reach the service at, without a trailing slash.
+    pub public_url: Option<String>,
+    pub data_path: PathBuf,
 }
 
 const METLO_DATA_PATH_DEFAULT: &str = "/tmp/metlo_csp/";
 
+/// `METLO_DATA_PATH`, created if missing.
+pub fn data_path() -> Result<PathBuf, std::io::Error> {
+    let path =
+        PathBuf::from(env::var("METLO_DATA_PATH").unwrap_or(METLO_DATA_PATH_DEFAULT.to_owned()));
+    fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+/// Opens the report database in `data_path`, bringing its schema up to date.
+pub fn open_duckdb(
+    data_path: &Path,
+) -> Result<r2d2::Pool<DuckdbConnectionManager>, Box<dyn std::error::Error + Send + Sync>> {
+    let manager = DuckdbConnectionManager::file(data_path.join("metlo_csp.duckdb"))?;
+    let duckdb_pool = r2d2::Pool::builder().max_size(4).build(manager).unwrap();
+    let mut conn = duckdb_pool.get()?;
+    migrate_duckdb(&mut conn)?;
+    let backfilled = rollup::backfill_fingerprints(&mut conn)?;
+    if backfilled > 0 {
+        info!("Fingerprinted {} existing distinct reports", backfilled);
+    }
+    drop(conn);
+    Ok(duckdb_pool)
+}
+
 impl AppState {
     pub async fn make_app_state() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let metlo_data_path =
-            env::var("METLO_DATA_PATH").unwrap_or(METLO_DATA_PATH_DEFAULT.to_owned());
-        fs::create_dir_all(&metlo_data_path)?;
-
-        let path = Path::new(&metlo_data_path);
+        let metlo_data_path = data_path()?;
+        let path = metlo_data_path.as_path();
         let db_conn_string = path.join("metlo_csp.db").to_string_lossy().to_string();
-        let duckdb_conn_string = path.join("metlo_csp.duckdb").to_string_lossy().to_string();
         let secret_key = env::var("METLO_SECRET_KEY")
             .map_err(|e| format!("Error getting METLO_SECRET_KEY: {}", e))?;
+        let trusted_proxies =
+            parse_trusted_proxies(&env::var("METLO_TRUSTED_PROXIES").unwrap_or_default())?;
+        let max_body_bytes: usize = env::var("METLO_MAX_BODY_BYTES")
+            .unwrap_or(MAX_BODY_BYTES_DEFAULT.to_string())
+            .parse()
+            .unwrap_or(MAX_BODY_BYTES_DEFAULT);
+        let public_url = match env::var("METLO_PUBLIC_URL") {
+            Ok(e) if e.starts_with("https://") || e.starts_with("http://") => {
+                Some(e.trim_end_matches('/').to_owned())
+            }
+            Ok(e) if !e.is_empty() => {
+                return Err(format!("METLO_PUBLIC_URL must be an http(s) URL, got {}", e).into())
+            }
+            _ => None,
+        };
+        let metrics = Arc::new(Metrics::default());
+        let ingest = IngestQueue::from_env(metrics.clone(), path)?;
 
         let cfg = Config::new(db_conn_string);
         let db_pool = cfg.create_pool(Runtime::Tokio1).unwrap();
@@ -31,45 +99,35 @@ impl AppState {
 
         let db_conn = db_pool.get().await?;
         db_conn
-            .interact(|conn| {
-                conn.execute(
-                    "CREATE TABLE IF NOT EXISTS api_token ( id INTEGER PRIMARY KEY, prefix TEXT NOT NULL, hash TEXT NOT NULL );",
-                    (),
-                )
-            })
+            .interact(migrate_sqlite)
             .await
-            .map_err(|e| format!("Error interacting with sqllilte: {}", e))?
-            .map_err(|e| format!("Error running sqllilte query: {}", e))?;
-
-        let manager = DuckdbConnectionManager::file(duckdb_conn_string)?;
-        let duckdb_pool = r2d2::Pool::builder().max_size(4).build(manager).unwrap();
+            .map_err(|e| format!("Error interacting with sqllilte: {}", e))??;
+        let project_keys = ProjectKeys::default();
+        project_keys
+            .reload(&db_pool)
+            .await
+            .map_err(|(_, e)| format!("Error loading projects: {}", e))?;
 
-        let duck_conn = duckdb_pool.get()?;
-        duck_conn.execute_batch(
-            r"CREATE SEQUENCE IF NOT EXISTS csp_report_seq;
-              CREATE TABLE IF NOT EXISTS csp_report (
-                source_ip TEXT NOT NULL,
-                created_at TIMESTAMP DEFAULT NOW(),
-                document_uri TEXT NOT NULL,
-                referrer TEXT NOT NULL,
-                violated_directive TEXT NOT NULL,
-                effective_directive TEXT NOT NULL,
-                original_policy TEXT NOT NULL,
-                disposition TEXT NOT NULL,
-                blocked_uri TEXT,
-                line_number UINTEGER,
-                column_number UINTEGER,
-                source_file TEXT,
-                status_code UINTEGER,
-                script_sample TEXT NOT NULL,
-              );
-             ",
-        )?;
+        let duckdb_pool = open_duckdb(path)?;
 
-        Ok(AppState {
+        let app_state = AppState {
             db_pool,
             duckdb_pool,
             secret_key,
-        })
+            trusted_proxies: Arc::new(trusted_proxies),
+            ingest,
+            metrics,
+            rate_limiter: Arc::new(RateLimiter::from_env()),
+            max_body_bytes,
+            noise_filter: Arc::new(NoiseFilter::from_env()?),
+            project_keys,
+            redactor: Arc::new(Redactor::from_env()?),
+            retention: Arc::new(Retention::from_env()?),
+            public_url,
+            data_path: metlo_data_path.clone(),
+        };
+        spool::replay(&app_state, path)?;
+
+        Ok(app_state)
     }
 }
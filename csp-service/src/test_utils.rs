@@ -0,0 +1,98 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use deadpool_sqlite::{Config, Runtime};
+
+use crate::{
+    filter::NoiseFilter,
+    ingest::{IngestQueue, OverflowPolicy},
+    metrics::Metrics,
+    migrations::migrate_sqlite,
+    payload::MAX_BODY_BYTES_DEFAULT,
+    project::ProjectKeys,
+    rate_limit::RateLimiter,
+    redact::Redactor,
+    retention::Retention,
+    sampler::Sampler,
+    spool::Spool,
+    state::{open_duckdb, AppState},
+};
+
+/// A directory under the system temp dir, removed again when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "metlo_csp_test_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// An in-memory DuckDB database with every migration applied.
+pub fn migrated_duckdb() -> duckdb::Connection {
+    let mut conn = duckdb::Connection::open_in_memory().unwrap();
+    crate::migrations::migrate_duckdb(&mut conn).unwrap();
+    conn
+}
+
+/// An `AppState` keeping its databases and spool in `dir`, with sampling,
+/// filtering, redaction, rate limits and retention all off.
+pub async fn app_state(dir: &Path) -> AppState {
+    let db_pool = Config::new(dir.join("metlo_csp.db"))
+        .create_pool(Runtime::Tokio1)
+        .unwrap();
+    db_pool
+        .get()
+        .await
+        .unwrap()
+        .interact(migrate_sqlite)
+        .await
+        .unwrap()
+        .unwrap();
+    let metrics = Arc::new(Metrics::default());
+    AppState {
+        db_pool,
+        duckdb_pool: open_duckdb(dir).unwrap(),
+        secret_key: "secret".to_string(),
+        trusted_proxies: Arc::new(vec![]),
+        ingest: IngestQueue::new(
+            metrics.clone(),
+            Spool::open(dir).unwrap(),
+            Sampler::new(0, 1),
+            100,
+            OverflowPolicy::Reject,
+        ),
+        metrics,
+        rate_limiter: Arc::new(RateLimiter::from_env()),
+        max_body_bytes: MAX_BODY_BYTES_DEFAULT,
+        noise_filter: Arc::new(NoiseFilter::new(vec![]).unwrap()),
+        project_keys: ProjectKeys::default(),
+        redactor: Arc::new(Redactor::default()),
+        retention: Arc::new(Retention::from_env().unwrap()),
+        public_url: None,
+        data_path: dir.to_path_buf(),
+    }
+}
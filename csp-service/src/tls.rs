@@ -0,0 +1,169 @@
+use std::{env, fs, path::PathBuf, time::Duration, time::SystemTime};
+
+use axum_server::tls_rustls::RustlsConfig;
+use log::{error, info};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The PEM certificate chain and private key TCP listeners serve TLS with.
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl TlsFiles {
+    /// `METLO_TLS_CERT_PATH` and `METLO_TLS_KEY_PATH`, TLS is off when neither is set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        TlsFiles::from_paths(
+            env::var("METLO_TLS_CERT_PATH").ok(),
+            env::var("METLO_TLS_KEY_PATH").ok(),
+        )
+    }
+
+    fn from_paths(cert: Option<String>, key: Option<String>) -> Result<Option<Self>, String> {
+        match (
+            cert.filter(|e| !e.is_empty()),
+            key.filter(|e| !e.is_empty()),
+        ) {
+            (Some(cert), Some(key)) => Ok(Some(TlsFiles {
+                cert: cert.into(),
+                key: key.into(),
+            })),
+            (None, None) => Ok(None),
+            _ => Err("METLO_TLS_CERT_PATH and METLO_TLS_KEY_PATH must be set together".to_string()),
+        }
+    }
+
+    pub async fn load(&self) -> Result<RustlsConfig, String> {
+        RustlsConfig::from_pem_file(&self.cert, &self.key)
+            .await
+            .map_err(|e| format!("Error loading TLS certificate: {}", e))
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &PathBuf| fs::metadata(path).and_then(|e| e.modified()).ok();
+        Some((modified(&self.cert)?, modified(&self.key)?))
+    }
+}
+
+/// Reloads the certificate when either file changes, so renewals (e.g. by
+/// certbot) apply to new connections without a restart. A pair that fails
+/// to load, say because only one file was replaced so far, is retried on the
+/// next tick while the old certificate stays in use.
+pub async fn run_reloader(config: RustlsConfig, files: TlsFiles) {
+    let mut loaded = files.modified();
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        reload_if_modified(&config, &files, &mut loaded).await;
+    }
+}
+
+/// Reloads `config` if the files changed since `loaded`, returning whether it did.
+async fn reload_if_modified(
+    config: &RustlsConfig,
+    files: &TlsFiles,
+    loaded: &mut Option<(SystemTime, SystemTime)>,
+) -> bool {
+    let modified = files.modified();
+    if modified.is_none() || modified == *loaded {
+        return false;
+    }
+    match config.reload_from_pem_file(&files.cert, &files.key).await {
+        Ok(_) => {
+            info!("Reloaded TLS certificate");
+            *loaded = modified;
+            true
+        }
+        Err(e) => {
+            error!("Error reloading TLS certificate: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    const CERT: &str = include_str!("testdata/cert.pem");
+    const KEY: &str = include_str!("testdata/key.pem");
+
+    fn files(dir: &TempDir) -> TlsFiles {
+        let files = TlsFiles {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+        };
+        fs::write(&files.cert, CERT).unwrap();
+        fs::write(&files.key, KEY).unwrap();
+        files
+    }
+
+    fn touch(path: &PathBuf, modified: SystemTime) {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn cert_and_key_must_be_set_together() {
+        let some = |e: &str| Some(e.to_string());
+        let files = TlsFiles::from_paths(some("cert.pem"), some("key.pem"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(files.cert, PathBuf::from("cert.pem"));
+        assert_eq!(files.key, PathBuf::from("key.pem"));
+        assert!(TlsFiles::from_paths(None, None).unwrap().is_none());
+        assert!(TlsFiles::from_paths(some(""), some("")).unwrap().is_none());
+        assert!(TlsFiles::from_paths(some("cert.pem"), None).is_err());
+        assert!(TlsFiles::from_paths(None, some("key.pem")).is_err());
+        assert!(TlsFiles::from_paths(some("cert.pem"), some("")).is_err());
+    }
+
+    #[tokio::test]
+    async fn reloads_when_the_files_change() {
+        let dir = TempDir::new();
+        let files = files(&dir);
+        let config = files.load().await.unwrap();
+        let mut loaded = files.modified();
+        let inner = config.get_inner();
+
+        assert!(!reload_if_modified(&config, &files, &mut loaded).await);
+        assert!(Arc::ptr_eq(&inner, &config.get_inner()));
+
+        let later = loaded.unwrap().0 + Duration::from_secs(60);
+        touch(&files.cert, later);
+        assert!(reload_if_modified(&config, &files, &mut loaded).await);
+        assert!(!Arc::ptr_eq(&inner, &config.get_inner()));
+        assert_eq!(loaded.unwrap().0, later);
+        assert!(!reload_if_modified(&config, &files, &mut loaded).await);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_old_certificate_until_the_pair_loads() {
+        let dir = TempDir::new();
+        let files = files(&dir);
+        let config = files.load().await.unwrap();
+        let mut loaded = files.modified();
+        let inner = config.get_inner();
+
+        // Only the key has been replaced so far, with something unusable.
+        fs::write(&files.key, "not a key").unwrap();
+        touch(&files.key, loaded.unwrap().1 + Duration::from_secs(60));
+        assert!(!reload_if_modified(&config, &files, &mut loaded).await);
+        assert!(Arc::ptr_eq(&inner, &config.get_inner()));
+
+        fs::write(&files.key, KEY).unwrap();
+        touch(&files.key, loaded.unwrap().1 + Duration::from_secs(120));
+        assert!(reload_if_modified(&config, &files, &mut loaded).await);
+        assert!(!Arc::ptr_eq(&inner, &config.get_inner()));
+    }
+}
@@ -0,0 +1,80 @@
+use lazy_static::lazy_static;
+use woothee::parser::Parser;
+
+lazy_static! {
+    static ref UA_PARSER: Parser = Parser::new();
+}
+
+const UNKNOWN: &str = "UNKNOWN";
+
+#[derive(Debug)]
+pub struct ParsedUserAgent {
+    pub browser_family: String,
+    pub browser_version: String,
+    pub os: String,
+}
+
+pub fn parse_user_agent(user_agent: &str) -> ParsedUserAgent {
+    match UA_PARSER.parse(user_agent) {
+        Some(res) => ParsedUserAgent {
+            browser_family: res.name.to_owned(),
+            browser_version: res
+                .version
+                .split('.')
+                .next()
+                .filter(|e| !e.is_empty())
+                .unwrap_or(UNKNOWN)
+                .to_owned(),
+            os: res.os.to_owned(),
+        },
+        None => ParsedUserAgent {
+            browser_family: UNKNOWN.to_owned(),
+            browser_version: UNKNOWN.to_owned(),
+            os: UNKNOWN.to_owned(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(user_agent: &str) -> (String, String, String) {
+        let res = parse_user_agent(user_agent);
+        (res.browser_family, res.browser_version, res.os)
+    }
+
+    #[test]
+    fn parses_known_browsers() {
+        assert_eq!(
+            parsed("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/114.0.5735.199 Safari/537.36"),
+            ("Chrome".to_string(), "114".to_string(), "Windows 10".to_string())
+        );
+        assert_eq!(
+            parsed("Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/115.0"),
+            (
+                "Firefox".to_string(),
+                "115".to_string(),
+                "Linux".to_string()
+            )
+        );
+        assert_eq!(
+            parsed("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.5.1 Safari/605.1.15"),
+            ("Safari".to_string(), "16".to_string(), "Mac OSX".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_user_agents() {
+        for user_agent in ["", "SomethingUnusual/1.0"] {
+            assert_eq!(
+                parsed(user_agent),
+                (
+                    "UNKNOWN".to_string(),
+                    "UNKNOWN".to_string(),
+                    "UNKNOWN".to_string()
+                )
+            );
+        }
+    }
+}